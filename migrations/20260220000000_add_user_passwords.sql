@@ -0,0 +1,3 @@
+-- Add password hash to users table
+-- Accounts created before this migration have a NULL hash and are rejected by login
+ALTER TABLE users ADD COLUMN password_hash TEXT;
@@ -0,0 +1,15 @@
+-- Add per-chat member roles
+ALTER TABLE chat_participants ADD COLUMN role TEXT NOT NULL DEFAULT 'member' CHECK(role IN ('owner', 'admin', 'member'));
+
+-- Existing groups get their longest-standing member as owner
+UPDATE chat_participants SET role = 'owner'
+WHERE rowid IN (
+    SELECT (
+        SELECT cp.rowid FROM chat_participants cp
+        WHERE cp.chat_id = c.id
+        ORDER BY cp.joined_at, cp.user_id
+        LIMIT 1
+    )
+    FROM chats c
+    WHERE c.chat_type = 'group'
+);
@@ -0,0 +1,30 @@
+-- Discord-style servers: a server owns text channels (chats with chat_type 'server')
+-- grouped into categories. Server members are mirrored into chat_participants of every channel.
+CREATE TABLE servers (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    created_at TEXT DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE server_members (
+    server_id INTEGER NOT NULL REFERENCES servers(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL DEFAULT 'member' CHECK(role IN ('owner', 'admin', 'member')),
+    joined_at TEXT DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (server_id, user_id)
+);
+
+CREATE TABLE server_categories (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    server_id INTEGER NOT NULL REFERENCES servers(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    position INTEGER NOT NULL DEFAULT 0
+);
+
+ALTER TABLE chats ADD COLUMN server_id INTEGER REFERENCES servers(id) ON DELETE CASCADE;
+ALTER TABLE chats ADD COLUMN category_id INTEGER REFERENCES server_categories(id) ON DELETE SET NULL;
+ALTER TABLE chats ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE chats ADD COLUMN archived_at TEXT;
+
+CREATE INDEX idx_chats_server_id ON chats(server_id);
+CREATE INDEX idx_server_members_user_id ON server_members(user_id);
@@ -0,0 +1,12 @@
+-- Track message edits
+ALTER TABLE messages ADD COLUMN edited_at TEXT;
+
+-- Previous versions of edited messages
+CREATE TABLE message_edits (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    content TEXT,
+    edited_at TEXT NOT NULL
+);
+
+CREATE INDEX idx_message_edits_message_id ON message_edits(message_id);
@@ -0,0 +1,10 @@
+-- Delete-for-everyone leaves a tombstone: content and attachments are removed, the row stays
+ALTER TABLE messages ADD COLUMN deleted_at TEXT;
+
+-- Delete-for-me hides a message from a single participant's history
+CREATE TABLE hidden_messages (
+    message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    hidden_at TEXT NOT NULL,
+    PRIMARY KEY (message_id, user_id)
+);
@@ -0,0 +1,2 @@
+-- Supports cursor pagination of chat history by message id
+CREATE INDEX idx_messages_chat_id_id ON messages(chat_id, id);
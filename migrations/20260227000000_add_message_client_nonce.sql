@@ -0,0 +1,5 @@
+-- Client-generated id making message sends idempotent across retries
+ALTER TABLE messages ADD COLUMN client_nonce TEXT;
+
+CREATE UNIQUE INDEX idx_messages_sender_client_nonce ON messages(sender_id, client_nonce)
+    WHERE client_nonce IS NOT NULL;
@@ -0,0 +1,10 @@
+-- Per-user log of WebSocket events so reconnecting clients can replay what they missed
+ALTER TABLE users ADD COLUMN event_seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE user_events (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    seq INTEGER NOT NULL,
+    frame TEXT NOT NULL, -- The event as sent to the client, including its seq
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (user_id, seq)
+);
@@ -0,0 +1,2 @@
+-- Time of the user's last WebSocket disconnect
+ALTER TABLE users ADD COLUMN last_seen_at TEXT;
@@ -0,0 +1,25 @@
+-- Newest message each participant has read; messages after it count as unread
+ALTER TABLE chat_participants ADD COLUMN last_read_message_id INTEGER REFERENCES messages(id) ON DELETE SET NULL;
+
+-- Existing history counts as read rather than flooding everyone with unread messages
+UPDATE chat_participants
+SET last_read_message_id = (SELECT MAX(m.id) FROM messages m WHERE m.chat_id = chat_participants.chat_id);
+
+-- Unread messages are those from other senders after the read marker that are still visible
+CREATE VIEW chat_read_states AS
+SELECT
+    cp.chat_id,
+    cp.user_id,
+    cp.last_read_message_id,
+    (
+        SELECT COUNT(*)
+        FROM messages m
+        WHERE m.chat_id = cp.chat_id
+          AND m.id > COALESCE(cp.last_read_message_id, 0)
+          AND m.sender_id != cp.user_id
+          AND m.deleted_at IS NULL
+          AND NOT EXISTS (
+            SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = cp.user_id
+          )
+    ) AS unread_count
+FROM chat_participants cp;
@@ -0,0 +1,32 @@
+-- When each recipient first had a message pushed to one of their connections
+CREATE TABLE message_deliveries (
+    message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    delivered_at TEXT NOT NULL,
+    PRIMARY KEY (message_id, user_id)
+);
+
+-- Aggregated status of each message across the other participants of its chat: 'read' once
+-- every read marker has passed it, 'delivered' once every recipient received or read it
+CREATE VIEW message_statuses AS
+SELECT
+    m.id AS message_id,
+    m.chat_id,
+    m.sender_id,
+    CASE
+        WHEN NOT EXISTS (
+            SELECT 1 FROM chat_participants cp
+            WHERE cp.chat_id = m.chat_id AND cp.user_id != m.sender_id
+              AND COALESCE(cp.last_read_message_id, 0) < m.id
+        ) THEN 'read'
+        WHEN NOT EXISTS (
+            SELECT 1 FROM chat_participants cp
+            WHERE cp.chat_id = m.chat_id AND cp.user_id != m.sender_id
+              AND COALESCE(cp.last_read_message_id, 0) < m.id
+              AND NOT EXISTS (
+                SELECT 1 FROM message_deliveries d WHERE d.message_id = m.id AND d.user_id = cp.user_id
+              )
+        ) THEN 'delivered'
+        ELSE 'sent'
+    END AS status
+FROM messages m;
@@ -0,0 +1,2 @@
+-- Message a message replies to, within the same chat
+ALTER TABLE messages ADD COLUMN reply_to INTEGER REFERENCES messages(id);
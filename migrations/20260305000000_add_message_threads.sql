@@ -0,0 +1,25 @@
+-- Root message of the thread a message was posted in; NULL for the main chat stream
+ALTER TABLE messages ADD COLUMN thread_root_id INTEGER REFERENCES messages(id);
+
+CREATE INDEX idx_messages_thread_root ON messages(thread_root_id, id) WHERE thread_root_id IS NOT NULL;
+
+-- Thread replies do not count as unread in the chat they were posted in
+DROP VIEW chat_read_states;
+CREATE VIEW chat_read_states AS
+SELECT
+    cp.chat_id,
+    cp.user_id,
+    cp.last_read_message_id,
+    (
+        SELECT COUNT(*)
+        FROM messages m
+        WHERE m.chat_id = cp.chat_id
+          AND m.thread_root_id IS NULL
+          AND m.id > COALESCE(cp.last_read_message_id, 0)
+          AND m.sender_id != cp.user_id
+          AND m.deleted_at IS NULL
+          AND NOT EXISTS (
+            SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = cp.user_id
+          )
+    ) AS unread_count
+FROM chat_participants cp;
@@ -0,0 +1,25 @@
+-- Images uploaded to a server that its members can react with
+CREATE TABLE custom_emoji (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    server_id INTEGER NOT NULL REFERENCES servers(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    file_id INTEGER NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TEXT NOT NULL,
+    UNIQUE (server_id, name)
+);
+
+-- Reactions of users to messages: either a unicode emoji or the id of a custom emoji
+CREATE TABLE message_reactions (
+    message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    emoji TEXT,
+    custom_emoji_id INTEGER REFERENCES custom_emoji(id) ON DELETE CASCADE,
+    created_at TEXT NOT NULL,
+    CHECK ((emoji IS NULL) != (custom_emoji_id IS NULL))
+);
+
+CREATE UNIQUE INDEX idx_message_reactions_emoji ON message_reactions(message_id, user_id, emoji)
+    WHERE emoji IS NOT NULL;
+CREATE UNIQUE INDEX idx_message_reactions_custom_emoji ON message_reactions(message_id, user_id, custom_emoji_id)
+    WHERE custom_emoji_id IS NOT NULL;
@@ -0,0 +1,5 @@
+-- Original sender and chat of forwarded messages. Either may be deleted after the message was
+-- forwarded, so whether a message is a forward is stored separately.
+ALTER TABLE messages ADD COLUMN forwarded BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE messages ADD COLUMN forwarded_from_sender_id INTEGER REFERENCES users(id) ON DELETE SET NULL;
+ALTER TABLE messages ADD COLUMN forwarded_from_chat_id INTEGER REFERENCES chats(id) ON DELETE SET NULL;
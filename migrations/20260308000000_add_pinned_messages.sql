@@ -0,0 +1,9 @@
+-- Messages pinned in their chat
+CREATE TABLE pinned_messages (
+    message_id INTEGER PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+    chat_id INTEGER NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    pinned_by INTEGER NOT NULL REFERENCES users(id),
+    pinned_at TEXT NOT NULL
+);
+
+CREATE INDEX idx_pinned_messages_chat ON pinned_messages(chat_id, pinned_at);
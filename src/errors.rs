@@ -12,20 +12,50 @@ pub enum AppError {
     BadRequest(String),
     InternalServerError(String),
     NotFound(String),
+    Conflict(String),
+}
+
+impl AppError {
+    /// Stable identifier of the error kind, sent to clients in WebSocket `error` frames.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::AuthError(_) => "unauthorized",
+            AppError::DatabaseError(_) => "database_error",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::InternalServerError(_) => "internal_server_error",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::AuthError(_) => StatusCode::UNAUTHORIZED,
+            AppError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+        }
+    }
+
+    pub fn into_message(self) -> String {
+        match self {
+            AppError::DatabaseError(e) => e.to_string(),
+            AppError::AuthError(msg)
+            | AppError::BadRequest(msg)
+            | AppError::InternalServerError(msg)
+            | AppError::NotFound(msg)
+            | AppError::Conflict(msg) => msg,
+        }
+    }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AppError::AuthError(msg) => (StatusCode::UNAUTHORIZED, msg),
-            AppError::DatabaseError(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
-            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
-            AppError::InternalServerError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
-            AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
-        };
-
+        let status = self.status_code();
         let body = Json(json!({
-            "error": error_message,
+            "error": self.into_message(),
         }));
 
         (status, body).into_response()
@@ -43,6 +73,3 @@ impl From<tokio::task::JoinError> for AppError {
         AppError::InternalServerError(err.to_string())
     }
 }
-
-// Add map_err support
-impl AppError {}
@@ -1,36 +1,62 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use axum::{
     extract::{
         ws::{Message as WsMessage, WebSocket},
-        FromRef, FromRequestParts, Multipart, Path, Query, State, WebSocketUpgrade,
+        ConnectInfo, FromRef, FromRequestParts, Multipart, Path, Query, State, WebSocketUpgrade,
     },
-    http::request::Parts,
+    http::{request::Parts, StatusCode},
     response::IntoResponse,
     Json, RequestPartsExt,
 };
 use axum_extra::{
-    headers::{authorization::Bearer, Authorization},
+    headers::{authorization::Bearer, Authorization, UserAgent},
     TypedHeader,
 };
-use futures::{sink::SinkExt, stream::StreamExt};
+use dashmap::mapref::entry::Entry;
+use futures::{
+    sink::SinkExt,
+    stream::{SplitSink, StreamExt},
+};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::broadcast;
+use sha2::{Digest, Sha256};
+use sqlx::SqliteConnection;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, mpsc::error::TrySendError, Notify};
+use tokio::time::Instant;
 
 use crate::models::{
-    AppState, AuthResponse, Chat, ChatId, ChatHistoryResponse, ChatType, Claims, CreateUser,
-    FileUploadResponse, InitiateChat, Message, User, UserId, UserSearchQuery, WsMessageIn,
+    AddMembers, AppState, AuthResponse, CategoryId, ChangePasswordRequest, Channel,
+    ChannelCategory, Chat, ChatHistoryResponse, ChatId, ChatListQuery, ChatListResponse,
+    ChatParticipant, ChatPermission, ChatRole, ChatSummary, ChatType, Claims, Connection,
+    ConnectionHandle, ConnectionId, CreateCategory, CreateChannel, CreateCustomEmoji,
+    CreateGroupChat, CreateServer, CreateUser, CustomEmoji, CustomEmojiId, DeleteMessageQuery,
+    EditMessage, EventSeq, FileType, FileUploadResponse, ForwardMessage, HistoryQuery,
+    InitiateChat, LoginRequest, MarkRead, MediaAsset, Message, MessageEdit, MessageId,
+    MessagePreview, MessageRow, MessageSearchQuery, MessageSearchResponse, MessageSearchResult,
+    MessageStatus, OutboundEvent, PinnedMessage, PresenceStatus, Reaction, ReactionSummary,
+    RefreshRequest, RenameChat, SequencedEvent, ServerId, ServerLayout, ServerMember, ServerTree,
+    Session, SessionId, ThreadHistoryResponse, TypingIndicator, UpdateChannel, UpdateMemberRole,
+    User, UserId, UserSearchQuery, UserSearchResponse, WsClientEnvelope, WsClientFrame,
+    WsConnectQuery, WsEvent, WsMessageIn,
 };
 use crate::{
     errors::AppError,
     models::{ChatStatus, InitiateDirectChatResponse},
 };
 
-const JWT_EXPIRATION: usize = 3600 * 24; // 24 hours
+const ACCESS_TOKEN_EXPIRATION: usize = 60 * 15; // 15 minutes
+const REFRESH_TOKEN_EXPIRATION: i64 = 3600 * 24 * 30; // 30 days
 
 #[derive(Clone)]
 pub struct AuthenticatedUser {
     pub user_id: UserId,
-    pub username: String,
+    pub session_id: SessionId,
 }
 
 #[axum::async_trait]
@@ -54,9 +80,52 @@ where
             &Validation::default(),
         )
         .map_err(|_| AppError::AuthError("Invalid token".to_string()))?;
+        let claims = token_data.claims;
+        let is_active = sqlx::query_scalar!(
+            "SELECT 1 FROM sessions WHERE id = ? AND user_id = ? AND revoked_at IS NULL",
+            claims.sid,
+            claims.user_id
+        )
+        .fetch_optional(&app_state.pool)
+        .await?
+        .is_some();
+        if !is_active {
+            return Err(AppError::AuthError("Session has been revoked".to_string()));
+        }
         Ok(AuthenticatedUser {
-            user_id: token_data.claims.user_id,
-            username: token_data.claims.username,
+            user_id: claims.user_id,
+            session_id: claims.sid,
+        })
+    }
+}
+
+/// Request metadata recorded against a session when it is created or refreshed.
+pub struct ClientInfo {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ip_address = parts
+            .extract::<ConnectInfo<SocketAddr>>()
+            .await
+            .ok()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+        let user_agent = parts
+            .extract::<TypedHeader<UserAgent>>()
+            .await
+            .ok()
+            .map(|TypedHeader(ua)| ua.to_string());
+        Ok(ClientInfo {
+            ip_address,
+            user_agent,
         })
     }
 }
@@ -113,46 +182,71 @@ pub async fn upload_handler(
     Err(AppError::BadRequest("No file provided".to_string()))
 }
 
-pub async fn login_handler(
-    State(state): State<AppState>,
-    Json(payload): Json<CreateUser>,
-) -> Result<Json<AuthResponse>, AppError> {
-    let user: Option<User> = sqlx::query_as!(
-        User,
-        r#"SELECT id as "id!", username as "username!", display_name, bio, image_id FROM users WHERE username = ?"#,
-        payload.username
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+fn validate_password(password: &str) -> Result<(), AppError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AppError::BadRequest(format!(
+            "Password must be at least {} characters long",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+async fn hash_password(password: String) -> Result<String, AppError> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|_| AppError::InternalServerError("Password hashing failed".to_string()))
+    })
+    .await?
+}
+
+async fn verify_password(password: String, password_hash: String) -> Result<bool, AppError> {
+    tokio::task::spawn_blocking(move || {
+        let parsed = PasswordHash::new(&password_hash).map_err(|_| {
+            AppError::InternalServerError("Stored password hash is invalid".to_string())
+        })?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok())
+    })
+    .await?
+}
+
+fn generate_refresh_token() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
     )
-    .fetch_optional(&state.pool)
-    .await?;
-    let user = match user {
-        Some(u) => u,
-        None => {
-            let id = sqlx::query_scalar!(
-                "INSERT INTO users (username) VALUES (?) RETURNING id",
-                payload.username
-            )
-            .fetch_one(&state.pool)
-            .await?;
+}
 
-            User {
-                id,
-                username: payload.username.clone(),
-                display_name: None,
-                bio: None,
-                image_id: None,
-            }
-        }
-    };
+fn hash_refresh_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn issue_token(
+    state: &AppState,
+    user_id: UserId,
+    username: &str,
+    session_id: SessionId,
+    refresh_token: String,
+) -> Result<AuthResponse, AppError> {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs() as usize;
 
     let claims = Claims {
-        sub: user.username.clone(),
-        user_id: user.id,
-        username: user.username.clone(),
-        exp: now + JWT_EXPIRATION,
+        sub: username.to_string(),
+        user_id,
+        username: username.to_string(),
+        sid: session_id,
+        exp: now + ACCESS_TOKEN_EXPIRATION,
     };
     let token = encode(
         &Header::default(),
@@ -160,86 +254,691 @@ pub async fn login_handler(
         &EncodingKey::from_secret(state.jwt_secret.as_bytes()),
     )
     .map_err(|_| AppError::InternalServerError("Token creation failed".to_string()))?;
-    Ok(Json(AuthResponse { token }))
+    Ok(AuthResponse {
+        token,
+        refresh_token,
+        expires_in: ACCESS_TOKEN_EXPIRATION,
+    })
+}
+
+async fn create_session(
+    state: &AppState,
+    user_id: UserId,
+    username: &str,
+    device_name: Option<String>,
+    client: ClientInfo,
+) -> Result<AuthResponse, AppError> {
+    let refresh_token = generate_refresh_token();
+    let refresh_token_hash = hash_refresh_token(&refresh_token);
+    let now = chrono::Utc::now();
+    let created_at = now.to_rfc3339();
+    let expires_at = (now + chrono::Duration::seconds(REFRESH_TOKEN_EXPIRATION)).to_rfc3339();
+    let session_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO sessions (user_id, refresh_token_hash, device_name, ip_address, user_agent, created_at, last_used_at, expires_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?) RETURNING id
+        "#,
+        user_id,
+        refresh_token_hash,
+        device_name,
+        client.ip_address,
+        client.user_agent,
+        created_at,
+        created_at,
+        expires_at
+    )
+    .fetch_one(&state.pool)
+    .await?;
+    issue_token(state, user_id, username, session_id, refresh_token)
+}
+
+pub async fn register_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<CreateUser>,
+) -> Result<Json<AuthResponse>, AppError> {
+    let username = payload.username.trim().to_string();
+    if username.is_empty() {
+        return Err(AppError::BadRequest(
+            "Username must not be empty".to_string(),
+        ));
+    }
+    validate_password(&payload.password)?;
+    let exists = sqlx::query_scalar!("SELECT 1 FROM users WHERE username = ?", username)
+        .fetch_optional(&state.pool)
+        .await?
+        .is_some();
+    if exists {
+        return Err(AppError::Conflict(format!(
+            "Username {} is already taken",
+            username
+        )));
+    }
+    let password_hash = hash_password(payload.password).await?;
+    let id = sqlx::query_scalar!(
+        "INSERT INTO users (username, password_hash) VALUES (?, ?) RETURNING id",
+        username,
+        password_hash
+    )
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| match e {
+        // A concurrent registration took the username after the check above
+        sqlx::Error::Database(db) if db.is_unique_violation() => {
+            AppError::Conflict(format!("Username {} is already taken", username))
+        }
+        e => e.into(),
+    })?;
+    Ok(Json(
+        create_session(&state, id, &username, payload.device_name, client).await?,
+    ))
+}
+
+pub async fn login_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<LoginRequest>,
+) -> Result<Json<AuthResponse>, AppError> {
+    let invalid = || AppError::AuthError("Invalid username or password".to_string());
+    // Trimmed like at registration
+    let username = payload.username.trim();
+    let row = sqlx::query!(
+        r#"SELECT id as "id!", username as "username!", password_hash FROM users WHERE username = ?"#,
+        username
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(invalid)?;
+    let password_hash = row.password_hash.ok_or_else(invalid)?;
+    if !verify_password(payload.password, password_hash).await? {
+        return Err(invalid());
+    }
+    Ok(Json(
+        create_session(&state, row.id, &row.username, payload.device_name, client).await?,
+    ))
+}
+
+pub async fn change_password_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    validate_password(&payload.new_password)?;
+    let password_hash =
+        sqlx::query_scalar!("SELECT password_hash FROM users WHERE id = ?", auth.user_id)
+            .fetch_optional(&state.pool)
+            .await?
+            .flatten()
+            .ok_or_else(|| AppError::AuthError("Current password is incorrect".to_string()))?;
+    if !verify_password(payload.current_password, password_hash).await? {
+        return Err(AppError::AuthError(
+            "Current password is incorrect".to_string(),
+        ));
+    }
+    let new_hash = hash_password(payload.new_password).await?;
+    sqlx::query!(
+        "UPDATE users SET password_hash = ? WHERE id = ?",
+        new_hash,
+        auth.user_id
+    )
+    .execute(&state.pool)
+    .await?;
+    revoke_sessions(&state, auth.user_id, Some(auth.session_id)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn refresh_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<RefreshRequest>,
+) -> Result<Json<AuthResponse>, AppError> {
+    let invalid = || AppError::AuthError("Invalid refresh token".to_string());
+    let refresh_token_hash = hash_refresh_token(&payload.refresh_token);
+    let row = sqlx::query!(
+        r#"
+        SELECT s.id as "id!", s.user_id as "user_id!", s.expires_at, u.username as "username!"
+        FROM sessions s
+        JOIN users u ON s.user_id = u.id
+        WHERE s.refresh_token_hash = ? AND s.revoked_at IS NULL
+        "#,
+        refresh_token_hash
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(invalid)?;
+    let expires_at = chrono::DateTime::parse_from_rfc3339(&row.expires_at).map_err(|_| {
+        AppError::InternalServerError("Stored session expiry is invalid".to_string())
+    })?;
+    if expires_at < chrono::Utc::now() {
+        return Err(AppError::AuthError("Refresh token has expired".to_string()));
+    }
+    let refresh_token = generate_refresh_token();
+    let new_hash = hash_refresh_token(&refresh_token);
+    let last_used_at = chrono::Utc::now().to_rfc3339();
+    // Guard on the old hash so two concurrent refreshes cannot both rotate the same token
+    let rotated = sqlx::query!(
+        r#"
+        UPDATE sessions
+        SET refresh_token_hash = ?, last_used_at = ?, ip_address = COALESCE(?, ip_address), user_agent = COALESCE(?, user_agent)
+        WHERE id = ? AND refresh_token_hash = ?
+        "#,
+        new_hash,
+        last_used_at,
+        client.ip_address,
+        client.user_agent,
+        row.id,
+        refresh_token_hash
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected();
+    if rotated == 0 {
+        return Err(invalid());
+    }
+    Ok(Json(issue_token(
+        &state,
+        row.user_id,
+        &row.username,
+        row.id,
+        refresh_token,
+    )?))
+}
+
+async fn revoke_sessions(
+    state: &AppState,
+    user_id: UserId,
+    except: Option<SessionId>,
+) -> Result<(), AppError> {
+    let revoked_at = chrono::Utc::now().to_rfc3339();
+    sqlx::query!(
+        r#"
+        UPDATE sessions SET revoked_at = ?
+        WHERE user_id = ? AND revoked_at IS NULL AND (? IS NULL OR id != ?)
+        "#,
+        revoked_at,
+        user_id,
+        except,
+        except
+    )
+    .execute(&state.pool)
+    .await?;
+    close_connections(state, user_id, |c| Some(c.session_id) != except);
+    Ok(())
+}
+
+pub async fn list_sessions_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+) -> Result<Json<Vec<Session>>, AppError> {
+    let mut sessions = sqlx::query_as::<_, Session>(
+        r#"
+        SELECT id, device_name, ip_address, user_agent, created_at, last_used_at
+        FROM sessions
+        WHERE user_id = ? AND revoked_at IS NULL
+        ORDER BY last_used_at DESC
+        "#,
+    )
+    .bind(auth.user_id)
+    .fetch_all(&state.pool)
+    .await?;
+    for session in &mut sessions {
+        session.current = session.id == auth.session_id;
+    }
+    Ok(Json(sessions))
+}
+
+pub async fn revoke_session_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(session_id): Path<SessionId>,
+) -> Result<StatusCode, AppError> {
+    let revoked_at = chrono::Utc::now().to_rfc3339();
+    let affected = sqlx::query!(
+        "UPDATE sessions SET revoked_at = ? WHERE id = ? AND user_id = ? AND revoked_at IS NULL",
+        revoked_at,
+        session_id,
+        auth.user_id
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected();
+    if affected == 0 {
+        return Err(AppError::NotFound(format!(
+            "Session with ID {} not found",
+            session_id
+        )));
+    }
+    close_connections(&state, auth.user_id, |c| c.session_id == session_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn revoke_all_sessions_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+) -> Result<StatusCode, AppError> {
+    revoke_sessions(&state, auth.user_id, None).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn logout_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+) -> Result<StatusCode, AppError> {
+    let revoked_at = chrono::Utc::now().to_rfc3339();
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at = ? WHERE id = ?",
+        revoked_at,
+        auth.session_id
+    )
+    .execute(&state.pool)
+    .await?;
+    close_connections(&state, auth.user_id, |c| c.session_id == auth.session_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+const DEFAULT_CHAT_LIST_LIMIT: i64 = 50;
+const MAX_CHAT_LIST_LIMIT: i64 = 100;
+
+/// Splits rows queried with a limit of `limit + 1` into a page of at most `limit` rows and whether
+/// there is a next page, which the extra row tells.
+fn paginate<T>(mut rows: Vec<T>, limit: i64) -> (Vec<T>, bool) {
+    let has_more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+    (rows, has_more)
 }
 
+/// Lists the caller's direct and group chats by latest activity (their newest visible message,
+/// or creation for empty chats), followed on the first page by their servers.
 pub async fn list_chats_handler(
     State(state): State<AppState>,
     auth: AuthenticatedUser,
-) -> Result<Json<Vec<Chat>>, AppError> {
+    Query(query): Query<ChatListQuery>,
+) -> Result<Json<ChatListResponse>, AppError> {
+    let limit = query.limit.unwrap_or(DEFAULT_CHAT_LIST_LIMIT);
+    if !(1..=MAX_CHAT_LIST_LIMIT).contains(&limit) {
+        return Err(AppError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_CHAT_LIST_LIMIT
+        )));
+    }
+    // Cursors are `<activity_at>|<chat_id>` of the last chat on the previous page
+    let cursor = match &query.cursor {
+        Some(cursor) => {
+            let parsed = cursor
+                .rsplit_once('|')
+                .and_then(|(activity_at, id)| Some((activity_at.to_string(), id.parse().ok()?)));
+            Some(parsed.ok_or_else(|| AppError::BadRequest("Invalid cursor".to_string()))?)
+        }
+        None => None,
+    };
+    let (cursor_activity, cursor_id): (Option<String>, Option<ChatId>) = cursor.unzip();
+    let fetch_limit = limit + 1;
+
     let rows = sqlx::query!(
         r#"
-        SELECT c.id as "id!", c.name, c.chat_type as "chat_type: ChatType", c.created_at as "created_at!"
-        FROM chats c
-        JOIN chat_participants cp ON c.id = cp.chat_id
-        WHERE cp.user_id = ?
-        ORDER BY c.created_at DESC
+        WITH user_chats AS (
+            SELECT c.id, c.name, c.chat_type, c.created_at, rs.last_read_message_id, rs.unread_count,
+                   (
+                       SELECT MAX(m.id) FROM messages m
+                       WHERE m.chat_id = c.id
+                         AND m.thread_root_id IS NULL
+                         AND NOT EXISTS (
+                           SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = rs.user_id
+                         )
+                   ) AS last_message_id
+            FROM chats c
+            JOIN chat_read_states rs ON c.id = rs.chat_id
+            WHERE rs.user_id = ? AND c.server_id IS NULL
+        ),
+        listed AS (
+            SELECT uc.*, lm.sender_id, lm.content, lm.timestamp, lm.deleted_at,
+                   (
+                       SELECT f.type FROM message_files mf JOIN files f ON f.id = mf.file_id
+                       WHERE mf.message_id = lm.id
+                       ORDER BY f.id LIMIT 1
+                   ) AS attachment,
+                   (SELECT json_group_array(user_id) FROM chat_participants WHERE chat_id = uc.id) AS participants,
+                   (SELECT COUNT(*) FROM pinned_messages WHERE chat_id = uc.id) AS pin_count,
+                   strftime('%Y-%m-%d %H:%M:%f', COALESCE(lm.timestamp, uc.created_at)) AS activity_at
+            FROM user_chats uc
+            LEFT JOIN messages lm ON lm.id = uc.last_message_id
+        )
+        SELECT id as "id!", name, chat_type as "chat_type!: ChatType", created_at as "created_at!",
+               last_read_message_id, unread_count as "unread_count!: i64",
+               last_message_id as "last_message_id?: MessageId", sender_id as "sender_id?: UserId", content, timestamp as "timestamp?: String",
+               deleted_at, attachment as "attachment?: FileType", participants as "participants!: String",
+               pin_count as "pin_count!: i64",
+               activity_at as "activity_at!: String"
+        FROM listed
+        WHERE ? IS NULL OR activity_at < ? OR (activity_at = ? AND id < ?)
+        ORDER BY activity_at DESC, id DESC
+        LIMIT ?
         "#,
-        auth.user_id
+        auth.user_id,
+        cursor_activity,
+        cursor_activity,
+        cursor_activity,
+        cursor_id,
+        fetch_limit
     )
     .fetch_all(&state.pool)
     .await?;
 
+    let (rows, has_more) = paginate(rows, limit);
+    let next_cursor = rows
+        .last()
+        .filter(|_| has_more)
+        .map(|row| format!("{}|{}", row.activity_at, row.id));
     let mut chats = Vec::new();
     for row in rows {
-        let participants = sqlx::query_scalar!(
-            "SELECT user_id FROM chat_participants WHERE chat_id = ?",
-            row.id
-        )
-        .fetch_all(&state.pool)
-        .await?;
-
-        chats.push(Chat {
-            id: row.id,
-            name: row.name,
-            chat_type: row.chat_type,
-            created_at: row.created_at,
-            participants,
+        let last_message = match (row.last_message_id, row.sender_id, row.timestamp) {
+            (Some(id), Some(sender_id), Some(timestamp)) => Some(MessagePreview {
+                id,
+                sender_id,
+                snippet: preview_snippet(row.content.filter(|_| row.deleted_at.is_none())),
+                timestamp,
+                attachment: row.attachment,
+                deleted: row.deleted_at.is_some(),
+            }),
+            _ => None,
+        };
+        chats.push(ChatSummary {
+            chat: Chat {
+                id: row.id,
+                name: row.name,
+                chat_type: row.chat_type,
+                created_at: row.created_at,
+                server_id: None,
+                participants: serde_json::from_str(&row.participants)
+                    .map_err(|e| AppError::InternalServerError(e.to_string()))?,
+                pin_count: row.pin_count,
+            },
+            last_read_message_id: row.last_read_message_id,
+            unread_count: row.unread_count,
+            last_message,
         });
     }
+    if query.cursor.is_some() {
+        return Ok(Json(ChatListResponse {
+            chats,
+            servers: Vec::new(),
+            next_cursor,
+        }));
+    }
+
+    let servers = fetch_member_server_trees(&state, auth.user_id).await?;
 
-    Ok(Json(chats))
+    Ok(Json(ChatListResponse {
+        chats,
+        servers,
+        next_cursor,
+    }))
 }
 
 pub async fn get_user_handler(
     State(state): State<AppState>,
+    _auth: AuthenticatedUser,
     Path(user_id): Path<UserId>,
 ) -> Result<Json<User>, AppError> {
-    let user = sqlx::query_as!(
+    // Presence is not stored; it is filled in from the live connections
+    let mut user = sqlx::query_as!(
         User,
-        r#"SELECT id as "id!", username as "username!", display_name, bio, image_id FROM users WHERE id = ?"#,
+        r#"
+        SELECT id as "id!", username as "username!", display_name, bio, image_id, last_seen_at,
+               'offline' as "presence!: PresenceStatus"
+        FROM users WHERE id = ?
+        "#,
         user_id
     )
     .fetch_optional(&state.pool)
     .await?
     .ok_or_else(|| AppError::NotFound(format!("User with ID {} not found", user_id)))?;
+    user.presence = presence_of(&state, user.id);
 
     Ok(Json(user))
 }
 
+const DEFAULT_USER_SEARCH_LIMIT: i64 = 20;
+const MAX_USER_SEARCH_LIMIT: i64 = 50;
+
+/// Escapes the LIKE wildcards in user input, for patterns using `ESCAPE '\'`.
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Parses a user search cursor, `<rank>|<username>` of the last user on the previous page.
+fn parse_user_search_cursor(cursor: &str) -> Result<(i64, String), AppError> {
+    cursor
+        .split_once('|')
+        .and_then(|(rank, username)| Some((rank.parse().ok()?, username.to_string())))
+        .ok_or_else(|| AppError::BadRequest("Invalid cursor".to_string()))
+}
+
+/// Searches users by username and display name. Username prefix matches come first, then
+/// display name prefix matches, then matches anywhere in either; each group is sorted by
+/// username.
 pub async fn search_users_handler(
     State(state): State<AppState>,
+    _auth: AuthenticatedUser,
     Query(query): Query<UserSearchQuery>,
-) -> Result<Json<Vec<User>>, AppError> {
-    let users = if let Some(username) = query.username {
-        let pattern = format!("%{}%", username);
-        sqlx::query_as!(
-            User,
-            r#"SELECT id as "id!", username as "username!", display_name, bio, image_id FROM users WHERE username LIKE ?"#,
-            pattern
-        )
-        .fetch_all(&state.pool)
-        .await?
-    } else {
-        sqlx::query_as!(
-            User,
-            r#"SELECT id as "id!", username as "username!", display_name, bio, image_id FROM users"#
+) -> Result<Json<UserSearchResponse>, AppError> {
+    let term = query.q.trim();
+    if term.is_empty() {
+        return Err(AppError::BadRequest(
+            "Search query must not be empty".to_string(),
+        ));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_USER_SEARCH_LIMIT);
+    if !(1..=MAX_USER_SEARCH_LIMIT).contains(&limit) {
+        return Err(AppError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_USER_SEARCH_LIMIT
+        )));
+    }
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(parse_user_search_cursor)
+        .transpose()?;
+    let (cursor_rank, cursor_username): (Option<i64>, Option<String>) = cursor.unzip();
+    let prefix = format!("{}%", escape_like(term));
+    let substring = format!("%{}%", escape_like(term));
+    let fetch_limit = limit + 1;
+
+    let rows = sqlx::query!(
+        r#"
+        WITH ranked AS (
+            SELECT id, username, display_name, bio, image_id, last_seen_at,
+                   CASE
+                       WHEN username LIKE ?1 ESCAPE '\' THEN 0
+                       WHEN display_name LIKE ?1 ESCAPE '\' THEN 1
+                       ELSE 2
+                   END AS rank
+            FROM users
+            WHERE username LIKE ?2 ESCAPE '\' OR display_name LIKE ?2 ESCAPE '\'
         )
-        .fetch_all(&state.pool)
-        .await?
+        SELECT id as "id!", username as "username!", display_name, bio, image_id, last_seen_at,
+               rank as "rank!: i64"
+        FROM ranked
+        WHERE ?3 IS NULL OR rank > ?3 OR (rank = ?3 AND username > ?4)
+        ORDER BY rank, username
+        LIMIT ?5
+        "#,
+        prefix,
+        substring,
+        cursor_rank,
+        cursor_username,
+        fetch_limit
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let (rows, has_more) = paginate(rows, limit);
+    let next_cursor = rows
+        .last()
+        .filter(|_| has_more)
+        .map(|row| format!("{}|{}", row.rank, row.username));
+    let users = rows
+        .into_iter()
+        .map(|row| User {
+            presence: presence_of(&state, row.id),
+            id: row.id,
+            username: row.username,
+            display_name: row.display_name,
+            bio: row.bio,
+            image_id: row.image_id,
+            last_seen_at: row.last_seen_at,
+        })
+        .collect();
+    Ok(Json(UserSearchResponse { users, next_cursor }))
+}
+
+const DEFAULT_SEARCH_LIMIT: i64 = 20;
+const MAX_SEARCH_LIMIT: i64 = 100;
+
+/// Turns free text into an FTS5 query matching all of its words, the last one as a prefix so
+/// that results show up while typing. Quoting each word keeps FTS5 operators in the input from
+/// being interpreted.
+fn fts_query(text: &str) -> Option<String> {
+    let words: Vec<String> = text
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+    if words.is_empty() {
+        return None;
+    }
+    Some(format!("{}*", words.join(" ")))
+}
+
+// Delimit matches in FTS5 snippets, before the snippet is escaped and they become `<mark>` tags
+const MATCH_START: char = '\u{E000}';
+const MATCH_END: char = '\u{E001}';
+
+/// Turns a snippet with delimited matches into HTML: the message text is escaped, since it is
+/// user content, and the matches are wrapped in `<mark>`.
+fn highlight_snippet(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            MATCH_START => html.push_str("<mark>"),
+            MATCH_END => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// Parses a search date bound into an RFC 3339 timestamp. Plain dates stand for the start of
+/// the day, or for the end of it when `end_of_day` is set.
+fn parse_search_date(value: &str, end_of_day: bool) -> Result<String, AppError> {
+    if let Ok(timestamp) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&chrono::Utc).to_rfc3339());
+    }
+    let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+        AppError::BadRequest(format!(
+            "Invalid date '{}', expected YYYY-MM-DD or an RFC 3339 timestamp",
+            value
+        ))
+    })?;
+    let date = if end_of_day {
+        date.succ_opt()
+            .ok_or_else(|| AppError::BadRequest(format!("Invalid date '{}'", value)))?
+    } else {
+        date
     };
+    Ok(date.and_time(chrono::NaiveTime::MIN).and_utc().to_rfc3339())
+}
+
+/// Searches the contents of messages in the caller's chats, newest first.
+pub async fn search_messages_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Query(query): Query<MessageSearchQuery>,
+) -> Result<Json<MessageSearchResponse>, AppError> {
+    let fts = fts_query(&query.q)
+        .ok_or_else(|| AppError::BadRequest("Search query must not be empty".to_string()))?;
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+        return Err(AppError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_SEARCH_LIMIT
+        )));
+    }
+    let from = query
+        .from
+        .as_deref()
+        .map(|from| parse_search_date(from, false))
+        .transpose()?;
+    let to = query
+        .to
+        .as_deref()
+        .map(|to| parse_search_date(to, true))
+        .transpose()?;
+    let fetch_limit = limit + 1;
+    let rows = sqlx::query!(
+        r#"
+        SELECT m.id as "id!", m.chat_id, m.sender_id, m.thread_root_id, m.timestamp,
+               snippet(messages_fts, 0, char(57344), char(57345), '…', 16) as "snippet!: String"
+        FROM messages_fts
+        JOIN messages m ON m.id = messages_fts.rowid
+        JOIN chat_participants cp ON cp.chat_id = m.chat_id AND cp.user_id = ?
+        WHERE messages_fts MATCH ?
+          AND m.deleted_at IS NULL
+          AND NOT EXISTS (
+            SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = cp.user_id
+          )
+          AND (? IS NULL OR m.chat_id = ?)
+          AND (? IS NULL OR m.sender_id = ?)
+          AND (? IS NULL OR julianday(m.timestamp) >= julianday(?))
+          AND (? IS NULL OR julianday(m.timestamp) < julianday(?))
+          AND (? IS NULL OR EXISTS (SELECT 1 FROM message_files mf WHERE mf.message_id = m.id) = ?)
+          AND (? IS NULL OR m.id < ?)
+        ORDER BY m.id DESC
+        LIMIT ?
+        "#,
+        auth.user_id,
+        fts,
+        query.chat_id,
+        query.chat_id,
+        query.sender_id,
+        query.sender_id,
+        from,
+        from,
+        to,
+        to,
+        query.has_attachment,
+        query.has_attachment,
+        query.cursor,
+        query.cursor,
+        fetch_limit
+    )
+    .fetch_all(&state.pool)
+    .await?;
 
-    Ok(Json(users))
+    let (rows, has_more) = paginate(rows, limit);
+    let next_cursor = rows.last().filter(|_| has_more).map(|row| row.id);
+    let results = rows
+        .into_iter()
+        .map(|row| MessageSearchResult {
+            message_id: row.id,
+            chat_id: row.chat_id,
+            sender_id: row.sender_id,
+            thread_root_id: row.thread_root_id,
+            timestamp: row.timestamp,
+            snippet: highlight_snippet(&row.snippet),
+        })
+        .collect();
+    Ok(Json(MessageSearchResponse {
+        results,
+        next_cursor,
+    }))
 }
 
 pub async fn initiate_direct_chat_handler(
@@ -247,9 +946,13 @@ pub async fn initiate_direct_chat_handler(
     auth: AuthenticatedUser,
     Json(payload): Json<InitiateChat>,
 ) -> Result<Json<InitiateDirectChatResponse>, AppError> {
-    let target: User = sqlx::query_as!(
+    let target = sqlx::query_as!(
         User,
-        r#"SELECT id as "id!", username as "username!", display_name, bio, image_id FROM users WHERE id = ?"#,
+        r#"
+        SELECT id as "id!", username as "username!", display_name, bio, image_id, last_seen_at,
+               'offline' as "presence!: PresenceStatus"
+        FROM users WHERE id = ?
+        "#,
         payload.target_id
     )
     .fetch_optional(&state.pool)
@@ -297,260 +1000,3529 @@ pub async fn initiate_direct_chat_handler(
     }))
 }
 
-async fn process_message(
-    state: &AppState,
-    auth: &AuthenticatedUser,
-    payload: WsMessageIn,
-) -> Result<(), AppError> {
-    let has_content = payload
-        .content
-        .as_ref()
-        .map(|s| !s.trim().is_empty())
-        .unwrap_or(false);
-    let files_in = payload.files.unwrap_or_default();
-    let has_files = !files_in.is_empty();
-    if !has_content && !has_files {
-        return Err(AppError::BadRequest(
-            "Message must have text or at least one file".to_string(),
-        ));
-    }
-    if files_in.len() > 10 {
+const MAX_CHAT_NAME_LENGTH: usize = 100;
+
+fn validate_chat_name(name: &str) -> Result<String, AppError> {
+    let name = name.trim();
+    if name.is_empty() {
         return Err(AppError::BadRequest(
-            "Maximum 10 files allowed per message".to_string(),
+            "Chat name must not be empty".to_string(),
         ));
     }
-    for file in &files_in {
-        if file.size_bytes > 10 * 1024 * 1024 {
-            return Err(AppError::BadRequest(format!(
-                "File {} exceeds 10MB limit",
-                file.filename
-            )));
-        }
+    if name.chars().count() > MAX_CHAT_NAME_LENGTH {
+        return Err(AppError::BadRequest(format!(
+            "Chat name must be at most {} characters long",
+            MAX_CHAT_NAME_LENGTH
+        )));
     }
-    let is_participant = sqlx::query_scalar!(
+    Ok(name.to_string())
+}
+
+async fn is_participant(
+    state: &AppState,
+    chat_id: ChatId,
+    user_id: UserId,
+) -> Result<bool, AppError> {
+    Ok(sqlx::query_scalar!(
         "SELECT 1 FROM chat_participants WHERE chat_id = ? AND user_id = ?",
-        payload.chat_id,
-        auth.user_id
+        chat_id,
+        user_id
     )
     .fetch_optional(&state.pool)
     .await?
-    .is_some();
-    if !is_participant {
+    .is_some())
+}
+
+async fn ensure_users_exist(state: &AppState, user_ids: &[UserId]) -> Result<(), AppError> {
+    for user_id in user_ids {
+        let exists = sqlx::query_scalar!("SELECT 1 FROM users WHERE id = ?", user_id)
+            .fetch_optional(&state.pool)
+            .await?
+            .is_some();
+        if !exists {
+            return Err(AppError::NotFound(format!(
+                "User with ID {} not found",
+                user_id
+            )));
+        }
+    }
+    Ok(())
+}
+
+async fn participant_role(
+    state: &AppState,
+    chat_id: ChatId,
+    user_id: UserId,
+) -> Result<Option<ChatRole>, AppError> {
+    Ok(sqlx::query_scalar!(
+        r#"SELECT role as "role: ChatRole" FROM chat_participants WHERE chat_id = ? AND user_id = ?"#,
+        chat_id,
+        user_id
+    )
+    .fetch_optional(&state.pool)
+    .await?)
+}
+
+fn require_permission(
+    chat: &Chat,
+    role: ChatRole,
+    permission: ChatPermission,
+) -> Result<(), AppError> {
+    if !role.allows(&chat.chat_type, permission) {
         return Err(AppError::AuthError(
-            "Not authorized to send to this chat".to_string(),
+            "Insufficient permissions in this chat".to_string(),
         ));
     }
-    let timestamp = chrono::Utc::now().to_rfc3339();
+    Ok(())
+}
+
+/// Loads a group chat the caller participates in, together with the caller's role.
+async fn fetch_group_chat(
+    state: &AppState,
+    chat_id: ChatId,
+    auth: &AuthenticatedUser,
+) -> Result<(Chat, ChatRole), AppError> {
+    let chat = fetch_chat(state, chat_id).await?;
+    let role = participant_role(state, chat_id, auth.user_id)
+        .await?
+        .ok_or_else(|| AppError::AuthError("Not authorized to manage this chat".to_string()))?;
+    if chat.chat_type != ChatType::Group {
+        return Err(AppError::BadRequest(
+            "Only group chats can be managed".to_string(),
+        ));
+    }
+    Ok((chat, role))
+}
+
+pub async fn create_group_chat_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Json(payload): Json<CreateGroupChat>,
+) -> Result<Json<Chat>, AppError> {
+    let name = validate_chat_name(&payload.name)?;
+    let mut member_ids = payload.member_ids;
+    member_ids.retain(|id| *id != auth.user_id);
+    member_ids.sort_unstable();
+    member_ids.dedup();
+    ensure_users_exist(&state, &member_ids).await?;
     let mut tx = state.pool.begin().await?;
-    let message_id = sqlx::query_scalar!(
-        "INSERT INTO messages (chat_id, sender_id, content, timestamp) VALUES (?, ?, ?, ?) RETURNING id",
-        payload.chat_id,
-        auth.user_id,
-        payload.content,
-        timestamp
+    let chat_id = sqlx::query_scalar!(
+        "INSERT INTO chats (name, chat_type) VALUES (?, ?) RETURNING id",
+        name,
+        "group"
     )
     .fetch_one(&mut *tx)
     .await?;
-    let mut db_files = Vec::new();
-    for file_in in files_in {
-        let file_id = sqlx::query_scalar!(
-            r#"
-            INSERT INTO files (type, url, filename, mime_type, size_bytes)
-            VALUES (?, ?, ?, ?, ?) RETURNING id
-            "#,
-            file_in.r#type,
-            file_in.url,
-            file_in.filename,
-            file_in.mime_type,
-            file_in.size_bytes
-        )
-        .fetch_one(&mut *tx)
-        .await?;
+    sqlx::query!(
+        "INSERT INTO chat_participants (chat_id, user_id, role) VALUES (?, ?, ?)",
+        chat_id,
+        auth.user_id,
+        ChatRole::Owner
+    )
+    .execute(&mut *tx)
+    .await?;
+    for user_id in member_ids {
         sqlx::query!(
-            "INSERT INTO message_files (message_id, file_id) VALUES (?, ?)",
-            message_id,
-            file_id
+            "INSERT INTO chat_participants (chat_id, user_id) VALUES (?, ?)",
+            chat_id,
+            user_id
         )
         .execute(&mut *tx)
         .await?;
-        db_files.push(crate::models::MediaAsset {
-            id: file_id,
-            r#type: file_in.r#type,
-            url: file_in.url,
-            filename: file_in.filename,
-            mime_type: file_in.mime_type,
-            size_bytes: file_in.size_bytes,
-            created_at: timestamp.clone(),
-        });
     }
     tx.commit().await?;
-    struct Participant {
-        username: String,
-    }
-    let participants = sqlx::query_as!(
-        Participant,
-        r#"
-        SELECT u.username as "username!"
-        FROM chat_participants cp
-        JOIN users u ON cp.user_id = u.id
-        WHERE cp.chat_id = ?
-        "#,
-        payload.chat_id
-    )
-    .fetch_all(&state.pool)
-    .await?;
-    let msg = Message {
-        id: message_id,
-        chat_id: payload.chat_id,
-        sender_id: auth.user_id,
-        content: payload.content,
-        timestamp,
-        files: db_files,
-    };
-    let msg_json = serde_json::to_string(&msg).unwrap();
-    for p in participants {
-        if let Some(sender_tx) = state.active_connections.get(&p.username) {
-            let _ = sender_tx.send(msg_json.clone());
-        }
-    }
-    Ok(())
+    let chat = fetch_chat(&state, chat_id).await?;
+    broadcast_to_chat(&state, chat_id, &WsEvent::ChatCreated(chat.clone())).await?;
+    Ok(Json(chat))
 }
 
-pub async fn get_chat_handler(
+pub async fn rename_chat_handler(
     State(state): State<AppState>,
     auth: AuthenticatedUser,
     Path(chat_id): Path<ChatId>,
+    Json(payload): Json<RenameChat>,
 ) -> Result<Json<Chat>, AppError> {
-    let is_participant = sqlx::query_scalar!(
-        "SELECT 1 FROM chat_participants WHERE chat_id = ? AND user_id = ?",
+    let (chat, role) = fetch_group_chat(&state, chat_id, &auth).await?;
+    require_permission(&chat, role, ChatPermission::RenameChat)?;
+    let name = validate_chat_name(&payload.name)?;
+    sqlx::query!("UPDATE chats SET name = ? WHERE id = ?", name, chat_id)
+        .execute(&state.pool)
+        .await?;
+    broadcast_to_chat(
+        &state,
         chat_id,
-        auth.user_id
+        &WsEvent::ChatRenamed {
+            chat_id,
+            name,
+            renamed_by: auth.user_id,
+        },
     )
-    .fetch_optional(&state.pool)
-    .await?
-    .is_some();
+    .await?;
+    Ok(Json(fetch_chat(&state, chat_id).await?))
+}
 
-    if !is_participant {
+pub async fn list_members_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(chat_id): Path<ChatId>,
+) -> Result<Json<Vec<ChatParticipant>>, AppError> {
+    if !is_participant(&state, chat_id, auth.user_id).await? {
         return Err(AppError::AuthError(
             "Not authorized to view this chat info".to_string(),
         ));
     }
+    Ok(Json(fetch_members(&state, chat_id).await?))
+}
 
-    let row = sqlx::query!(
+async fn fetch_members(
+    state: &AppState,
+    chat_id: ChatId,
+) -> Result<Vec<ChatParticipant>, AppError> {
+    Ok(sqlx::query_as!(
+        ChatParticipant,
         r#"
-        SELECT id as "id!", name, chat_type as "chat_type: ChatType", created_at as "created_at!"
-        FROM chats
-        WHERE id = ?
+        SELECT chat_id as "chat_id!", user_id as "user_id!", role as "role: ChatRole", joined_at as "joined_at!"
+        FROM chat_participants
+        WHERE chat_id = ?
+        ORDER BY joined_at, rowid
         "#,
         chat_id
     )
-    .fetch_optional(&state.pool)
-    .await?
-    .ok_or_else(|| AppError::NotFound(format!("Chat with ID {} not found", chat_id)))?;
-
-    let participants = sqlx::query_scalar!(
-        "SELECT user_id FROM chat_participants WHERE chat_id = ?",
-        chat_id
-    )
     .fetch_all(&state.pool)
-    .await?;
-
-    Ok(Json(Chat {
-        id: row.id,
-        name: row.name,
-        chat_type: row.chat_type,
-        created_at: row.created_at,
-        participants,
-    }))
+    .await?)
 }
 
-pub async fn get_history_handler(
+pub async fn add_members_handler(
     State(state): State<AppState>,
     auth: AuthenticatedUser,
     Path(chat_id): Path<ChatId>,
-) -> Result<Json<ChatHistoryResponse>, AppError> {
-    let is_participant = sqlx::query_scalar!(
-        "SELECT 1 FROM chat_participants WHERE chat_id = ? AND user_id = ?",
+    Json(payload): Json<AddMembers>,
+) -> Result<Json<Chat>, AppError> {
+    let (chat, role) = fetch_group_chat(&state, chat_id, &auth).await?;
+    require_permission(&chat, role, ChatPermission::ManageMembers)?;
+    let mut user_ids = payload.user_ids;
+    user_ids.retain(|id| !chat.participants.contains(id));
+    user_ids.sort_unstable();
+    user_ids.dedup();
+    if user_ids.is_empty() {
+        return Ok(Json(chat));
+    }
+    ensure_users_exist(&state, &user_ids).await?;
+    let mut tx = state.pool.begin().await?;
+    for user_id in &user_ids {
+        sqlx::query!(
+            "INSERT OR IGNORE INTO chat_participants (chat_id, user_id) VALUES (?, ?)",
+            chat_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    broadcast_to_chat(
+        &state,
         chat_id,
-        auth.user_id
+        &WsEvent::MembersAdded {
+            chat_id,
+            user_ids,
+            added_by: auth.user_id,
+        },
     )
-    .fetch_optional(&state.pool)
+    .await?;
+    Ok(Json(fetch_chat(&state, chat_id).await?))
+}
+
+/// Removes a participant and notifies both the remaining members and the removed user.
+/// If the owner leaves, ownership passes to the longest-standing admin, or failing that the
+/// longest-standing member. A group left without participants is deleted.
+async fn remove_member(
+    state: &AppState,
+    chat_id: ChatId,
+    user_id: UserId,
+    removed_by: UserId,
+) -> Result<(), AppError> {
+    let mut tx = state.pool.begin().await?;
+    let role = sqlx::query_scalar!(
+        r#"DELETE FROM chat_participants WHERE chat_id = ? AND user_id = ? RETURNING role as "role: ChatRole""#,
+        chat_id,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
     .await?
-    .is_some();
-    if !is_participant {
-        return Err(AppError::AuthError(
-            "Not authorized to view this chat".to_string(),
-        ));
-    }
-    let mut messages = sqlx::query_as::<_, Message>(
-        r#"
-        SELECT id, chat_id, sender_id, content, timestamp
-        FROM messages
-        WHERE chat_id = ?
-        ORDER BY timestamp ASC
-        "#,
+    .ok_or_else(|| {
+        AppError::NotFound(format!(
+            "User with ID {} is not a member of this chat",
+            user_id
+        ))
+    })?;
+    let remaining = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM chat_participants WHERE chat_id = ?",
+        chat_id
     )
-    .bind(chat_id)
-    .fetch_all(&state.pool)
+    .fetch_one(&mut *tx)
     .await?;
-    for msg in &mut messages {
-        let files = sqlx::query_as!(
-            crate::models::MediaAsset,
+    let mut new_owner = None;
+    if remaining == 0 {
+        sqlx::query!("DELETE FROM chats WHERE id = ?", chat_id)
+            .execute(&mut *tx)
+            .await?;
+    } else if role == ChatRole::Owner {
+        new_owner = sqlx::query_scalar!(
             r#"
-            SELECT f.id as "id!", f.type as "type: crate::models::FileType", f.url as "url!", f.filename as "filename!", f.mime_type, f.size_bytes as "size_bytes!", f.created_at as "created_at!"
-            FROM files f
-            JOIN message_files mf ON f.id = mf.file_id
-            WHERE mf.message_id = ?
+            UPDATE chat_participants SET role = 'owner'
+            WHERE chat_id = ? AND user_id = (
+                SELECT user_id FROM chat_participants
+                WHERE chat_id = ?
+                ORDER BY CASE role WHEN 'admin' THEN 0 ELSE 1 END, joined_at, rowid
+                LIMIT 1
+            )
+            RETURNING user_id
             "#,
-            msg.id
+            chat_id,
+            chat_id
         )
-        .fetch_all(&state.pool)
+        .fetch_optional(&mut *tx)
         .await?;
-        msg.files = files;
     }
-    Ok(Json(ChatHistoryResponse {
+    tx.commit().await?;
+    let event = WsEvent::MemberRemoved {
         chat_id,
-        messages,
-    }))
+        user_id,
+        removed_by,
+    };
+    broadcast_to_chat(state, chat_id, &event).await?;
+    send_to_user(state, user_id, &event).await?;
+    if let Some(new_owner) = new_owner {
+        broadcast_to_chat(
+            state,
+            chat_id,
+            &WsEvent::MemberRoleChanged {
+                chat_id,
+                user_id: new_owner,
+                role: ChatRole::Owner,
+                changed_by: user_id,
+            },
+        )
+        .await?;
+    }
+    Ok(())
 }
 
-pub async fn ws_handler(
+pub async fn remove_member_handler(
     State(state): State<AppState>,
     auth: AuthenticatedUser,
-    ws: WebSocketUpgrade,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state, auth))
+    Path((chat_id, user_id)): Path<(ChatId, UserId)>,
+) -> Result<StatusCode, AppError> {
+    let (chat, role) = fetch_group_chat(&state, chat_id, &auth).await?;
+    require_permission(&chat, role, ChatPermission::ManageMembers)?;
+    if user_id == auth.user_id {
+        return Err(AppError::BadRequest(
+            "Use the leave endpoint to leave a chat".to_string(),
+        ));
+    }
+    let target_role = participant_role(&state, chat_id, user_id)
+        .await?
+        .ok_or_else(|| {
+            AppError::NotFound(format!(
+                "User with ID {} is not a member of this chat",
+                user_id
+            ))
+        })?;
+    if target_role >= role {
+        return Err(AppError::AuthError(
+            "Cannot remove a member with an equal or higher role".to_string(),
+        ));
+    }
+    remove_member(&state, chat_id, user_id, auth.user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
-async fn handle_socket(socket: WebSocket, state: AppState, auth: AuthenticatedUser) {
-    let (mut sender, mut receiver) = socket.split();
-    let tx = state
-        .active_connections
-        .entry(auth.username.clone())
-        .or_insert_with(|| {
-            let (tx, _rx) = broadcast::channel(100);
-            tx
+pub async fn leave_chat_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(chat_id): Path<ChatId>,
+) -> Result<StatusCode, AppError> {
+    fetch_group_chat(&state, chat_id, &auth).await?;
+    remove_member(&state, chat_id, auth.user_id, auth.user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Changes a member's role. Assigning `owner` transfers ownership and demotes the caller to admin.
+pub async fn update_member_role_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((chat_id, user_id)): Path<(ChatId, UserId)>,
+    Json(payload): Json<UpdateMemberRole>,
+) -> Result<Json<Vec<ChatParticipant>>, AppError> {
+    let (chat, role) = fetch_group_chat(&state, chat_id, &auth).await?;
+    require_permission(&chat, role, ChatPermission::ManageRoles)?;
+    if user_id == auth.user_id {
+        return Err(AppError::BadRequest(
+            "Cannot change your own role".to_string(),
+        ));
+    }
+    if participant_role(&state, chat_id, user_id).await?.is_none() {
+        return Err(AppError::NotFound(format!(
+            "User with ID {} is not a member of this chat",
+            user_id
+        )));
+    }
+    let mut changes = vec![(user_id, payload.role)];
+    if payload.role == ChatRole::Owner {
+        changes.push((auth.user_id, ChatRole::Admin));
+    }
+    let mut tx = state.pool.begin().await?;
+    for (member_id, new_role) in &changes {
+        sqlx::query!(
+            "UPDATE chat_participants SET role = ? WHERE chat_id = ? AND user_id = ?",
+            new_role,
+            chat_id,
+            member_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    for (member_id, new_role) in changes {
+        broadcast_to_chat(
+            &state,
+            chat_id,
+            &WsEvent::MemberRoleChanged {
+                chat_id,
+                user_id: member_id,
+                role: new_role,
+                changed_by: auth.user_id,
+            },
+        )
+        .await?;
+    }
+    Ok(Json(fetch_members(&state, chat_id).await?))
+}
+
+async fn server_role(
+    state: &AppState,
+    server_id: ServerId,
+    user_id: UserId,
+) -> Result<Option<ChatRole>, AppError> {
+    Ok(sqlx::query_scalar!(
+        r#"SELECT role as "role: ChatRole" FROM server_members WHERE server_id = ? AND user_id = ?"#,
+        server_id,
+        user_id
+    )
+    .fetch_optional(&state.pool)
+    .await?)
+}
+
+/// Checks that the caller is a member of the server and, if given, holds `permission` there.
+async fn require_server_role(
+    state: &AppState,
+    server_id: ServerId,
+    auth: &AuthenticatedUser,
+    permission: Option<ChatPermission>,
+) -> Result<ChatRole, AppError> {
+    let role = server_role(state, server_id, auth.user_id)
+        .await?
+        .ok_or_else(|| AppError::AuthError("Not a member of this server".to_string()))?;
+    if let Some(permission) = permission {
+        if !role.allows(&ChatType::Server, permission) {
+            return Err(AppError::AuthError(
+                "Insufficient permissions in this server".to_string(),
+            ));
+        }
+    }
+    Ok(role)
+}
+
+/// Loads a server with its categories and channels as seen by `user_id`, whose role it is.
+async fn fetch_server_tree(
+    state: &AppState,
+    server_id: ServerId,
+    user_id: UserId,
+    role: ChatRole,
+) -> Result<ServerTree, AppError> {
+    let server = sqlx::query!(
+        r#"SELECT id as "id!", name as "name!", created_at as "created_at!" FROM servers WHERE id = ?"#,
+        server_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Server with ID {} not found", server_id)))?;
+    let category_rows = sqlx::query!(
+        r#"
+        SELECT id as "id!", name as "name!", position as "position!"
+        FROM server_categories
+        WHERE server_id = ?
+        ORDER BY position, id
+        "#,
+        server_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let channels = sqlx::query_as!(
+        Channel,
+        r#"
+        SELECT c.id as "id!", c.name, c.category_id, c.position as "position!", c.archived_at,
+               rs.last_read_message_id, COALESCE(rs.unread_count, 0) as "unread_count!: i64"
+        FROM chats c
+        LEFT JOIN chat_read_states rs ON rs.chat_id = c.id AND rs.user_id = ?
+        WHERE c.server_id = ?
+        ORDER BY c.position, c.id
+        "#,
+        user_id,
+        server_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let categories = category_rows
+        .into_iter()
+        .map(|row| ChannelCategory {
+            id: row.id,
+            name: row.name,
+            position: row.position,
+            channels: Vec::new(),
         })
-        .clone();
-    let mut rx = tx.subscribe();
-    let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if let Err(_e) = sender.send(WsMessage::Text(msg)).await {
-                // Client disconnected
-                break;
-            }
+        .collect();
+    Ok(build_server_tree(
+        server.id,
+        server.name,
+        server.created_at,
+        role,
+        categories,
+        channels,
+    ))
+}
+
+/// Sorts the (already ordered) channels of a server into their categories.
+fn build_server_tree(
+    id: ServerId,
+    name: String,
+    created_at: String,
+    role: ChatRole,
+    mut categories: Vec<ChannelCategory>,
+    channels: Vec<Channel>,
+) -> ServerTree {
+    let mut uncategorized = Vec::new();
+    for channel in channels {
+        match categories
+            .iter_mut()
+            .find(|category| Some(category.id) == channel.category_id)
+        {
+            Some(category) => category.channels.push(channel),
+            None => uncategorized.push(channel),
         }
-    });
-    let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            match msg {
-                WsMessage::Text(text) => {
-                    if let Ok(payload) = serde_json::from_str::<WsMessageIn>(&text) {
-                        if let Err(e) = process_message(&state, &auth, payload).await {
-                            tracing::error!("Failed to process WS message: {:?}", e);
-                            // Optionally send error back to user via WS?
-                        }
-                    }
+    }
+    ServerTree {
+        id,
+        name,
+        created_at,
+        role,
+        categories,
+        channels: uncategorized,
+    }
+}
+
+/// Loads the trees of all servers the user is a member of, sorted by name, with one query
+/// each for servers, categories and channels.
+async fn fetch_member_server_trees(
+    state: &AppState,
+    user_id: UserId,
+) -> Result<Vec<ServerTree>, AppError> {
+    let servers = sqlx::query!(
+        r#"
+        SELECT s.id as "id!", s.name as "name!", s.created_at as "created_at!", sm.role as "role: ChatRole"
+        FROM server_members sm
+        JOIN servers s ON sm.server_id = s.id
+        WHERE sm.user_id = ?
+        ORDER BY s.name
+        "#,
+        user_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let category_rows = sqlx::query!(
+        r#"
+        SELECT sc.server_id as "server_id!", sc.id as "id!", sc.name as "name!", sc.position as "position!"
+        FROM server_categories sc
+        JOIN server_members sm ON sm.server_id = sc.server_id AND sm.user_id = ?
+        ORDER BY sc.position, sc.id
+        "#,
+        user_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let channel_rows = sqlx::query!(
+        r#"
+        SELECT c.server_id as "server_id!", c.id as "id!", c.name, c.category_id, c.position as "position!",
+               c.archived_at, rs.last_read_message_id, COALESCE(rs.unread_count, 0) as "unread_count!: i64"
+        FROM chats c
+        JOIN server_members sm ON sm.server_id = c.server_id AND sm.user_id = ?
+        LEFT JOIN chat_read_states rs ON rs.chat_id = c.id AND rs.user_id = sm.user_id
+        ORDER BY c.position, c.id
+        "#,
+        user_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let mut categories: HashMap<ServerId, Vec<ChannelCategory>> = HashMap::new();
+    for row in category_rows {
+        categories
+            .entry(row.server_id)
+            .or_default()
+            .push(ChannelCategory {
+                id: row.id,
+                name: row.name,
+                position: row.position,
+                channels: Vec::new(),
+            });
+    }
+    let mut channels: HashMap<ServerId, Vec<Channel>> = HashMap::new();
+    for row in channel_rows {
+        channels.entry(row.server_id).or_default().push(Channel {
+            id: row.id,
+            name: row.name,
+            category_id: row.category_id,
+            position: row.position,
+            archived_at: row.archived_at,
+            last_read_message_id: row.last_read_message_id,
+            unread_count: row.unread_count,
+        });
+    }
+    Ok(servers
+        .into_iter()
+        .map(|server| {
+            build_server_tree(
+                server.id,
+                server.name,
+                server.created_at,
+                server.role,
+                categories.remove(&server.id).unwrap_or_default(),
+                channels.remove(&server.id).unwrap_or_default(),
+            )
+        })
+        .collect())
+}
+
+async fn broadcast_to_server(
+    state: &AppState,
+    server_id: ServerId,
+    event: &WsEvent,
+) -> Result<(), AppError> {
+    let recipients = sqlx::query_scalar!(
+        "SELECT user_id FROM server_members WHERE server_id = ?",
+        server_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    publish(state, &recipients, event).await
+}
+
+async fn notify_server_updated(
+    state: &AppState,
+    server_id: ServerId,
+    updated_by: UserId,
+) -> Result<(), AppError> {
+    broadcast_to_server(
+        state,
+        server_id,
+        &WsEvent::ServerUpdated {
+            server_id,
+            updated_by,
+        },
+    )
+    .await
+}
+
+/// Sets a member's server role and mirrors it onto every channel of the server.
+async fn set_server_role(
+    conn: &mut SqliteConnection,
+    server_id: ServerId,
+    user_id: UserId,
+    role: ChatRole,
+) -> Result<(), AppError> {
+    sqlx::query!(
+        "UPDATE server_members SET role = ? WHERE server_id = ? AND user_id = ?",
+        role,
+        server_id,
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE chat_participants SET role = ?
+        WHERE user_id = ? AND chat_id IN (SELECT id FROM chats WHERE server_id = ?)
+        "#,
+        role,
+        user_id,
+        server_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+async fn create_channel(
+    conn: &mut SqliteConnection,
+    server_id: ServerId,
+    name: &str,
+    category_id: Option<CategoryId>,
+) -> Result<ChatId, AppError> {
+    let chat_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO chats (name, chat_type, server_id, category_id, position)
+        VALUES (?, 'server', ?, ?, (SELECT COALESCE(MAX(position), -1) + 1 FROM chats WHERE server_id = ?))
+        RETURNING id as "id!"
+        "#,
+        name,
+        server_id,
+        category_id,
+        server_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO chat_participants (chat_id, user_id, role)
+        SELECT ?, user_id, role FROM server_members WHERE server_id = ?
+        "#,
+        chat_id,
+        server_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(chat_id)
+}
+
+pub async fn create_server_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Json(payload): Json<CreateServer>,
+) -> Result<Json<ServerTree>, AppError> {
+    let name = validate_chat_name(&payload.name)?;
+    let mut tx = state.pool.begin().await?;
+    let server_id = sqlx::query_scalar!("INSERT INTO servers (name) VALUES (?) RETURNING id", name)
+        .fetch_one(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO server_members (server_id, user_id, role) VALUES (?, ?, ?)",
+        server_id,
+        auth.user_id,
+        ChatRole::Owner
+    )
+    .execute(&mut *tx)
+    .await?;
+    create_channel(&mut tx, server_id, "general", None).await?;
+    tx.commit().await?;
+    Ok(Json(
+        fetch_server_tree(&state, server_id, auth.user_id, ChatRole::Owner).await?,
+    ))
+}
+
+pub async fn get_server_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(server_id): Path<ServerId>,
+) -> Result<Json<ServerTree>, AppError> {
+    let role = require_server_role(&state, server_id, &auth, None).await?;
+    Ok(Json(
+        fetch_server_tree(&state, server_id, auth.user_id, role).await?,
+    ))
+}
+
+async fn fetch_server_members(
+    state: &AppState,
+    server_id: ServerId,
+) -> Result<Vec<ServerMember>, AppError> {
+    Ok(sqlx::query_as!(
+        ServerMember,
+        r#"
+        SELECT server_id as "server_id!", user_id as "user_id!", role as "role: ChatRole", joined_at as "joined_at!"
+        FROM server_members
+        WHERE server_id = ?
+        ORDER BY joined_at, rowid
+        "#,
+        server_id
+    )
+    .fetch_all(&state.pool)
+    .await?)
+}
+
+pub async fn list_server_members_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(server_id): Path<ServerId>,
+) -> Result<Json<Vec<ServerMember>>, AppError> {
+    require_server_role(&state, server_id, &auth, None).await?;
+    Ok(Json(fetch_server_members(&state, server_id).await?))
+}
+
+pub async fn add_server_members_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(server_id): Path<ServerId>,
+    Json(payload): Json<AddMembers>,
+) -> Result<Json<Vec<ServerMember>>, AppError> {
+    require_server_role(
+        &state,
+        server_id,
+        &auth,
+        Some(ChatPermission::ManageMembers),
+    )
+    .await?;
+    let mut user_ids = payload.user_ids;
+    user_ids.sort_unstable();
+    user_ids.dedup();
+    ensure_users_exist(&state, &user_ids).await?;
+    let mut tx = state.pool.begin().await?;
+    for user_id in &user_ids {
+        let added = sqlx::query!(
+            "INSERT OR IGNORE INTO server_members (server_id, user_id) VALUES (?, ?)",
+            server_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if added == 0 {
+            continue;
+        }
+        sqlx::query!(
+            r#"
+            INSERT OR IGNORE INTO chat_participants (chat_id, user_id)
+            SELECT id, ? FROM chats WHERE server_id = ?
+            "#,
+            user_id,
+            server_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    notify_server_updated(&state, server_id, auth.user_id).await?;
+    Ok(Json(fetch_server_members(&state, server_id).await?))
+}
+
+/// Removes a server member from the server and all of its channels, passing ownership on
+/// like `remove_member` does for groups. A server left without members is deleted.
+async fn remove_server_member(
+    state: &AppState,
+    server_id: ServerId,
+    user_id: UserId,
+    removed_by: UserId,
+) -> Result<(), AppError> {
+    let mut tx = state.pool.begin().await?;
+    let role = sqlx::query_scalar!(
+        r#"DELETE FROM server_members WHERE server_id = ? AND user_id = ? RETURNING role as "role: ChatRole""#,
+        server_id,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| {
+        AppError::NotFound(format!(
+            "User with ID {} is not a member of this server",
+            user_id
+        ))
+    })?;
+    sqlx::query!(
+        r#"
+        DELETE FROM chat_participants
+        WHERE user_id = ? AND chat_id IN (SELECT id FROM chats WHERE server_id = ?)
+        "#,
+        user_id,
+        server_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    let successor = sqlx::query_scalar!(
+        r#"
+        SELECT user_id FROM server_members
+        WHERE server_id = ?
+        ORDER BY CASE role WHEN 'admin' THEN 0 ELSE 1 END, joined_at, rowid
+        LIMIT 1
+        "#,
+        server_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    match successor {
+        None => {
+            sqlx::query!("DELETE FROM servers WHERE id = ?", server_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        Some(successor) if role == ChatRole::Owner => {
+            set_server_role(&mut tx, server_id, successor, ChatRole::Owner).await?;
+        }
+        Some(_) => {}
+    }
+    tx.commit().await?;
+    let event = WsEvent::ServerUpdated {
+        server_id,
+        updated_by: removed_by,
+    };
+    broadcast_to_server(state, server_id, &event).await?;
+    send_to_user(state, user_id, &event).await?;
+    Ok(())
+}
+
+pub async fn remove_server_member_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((server_id, user_id)): Path<(ServerId, UserId)>,
+) -> Result<StatusCode, AppError> {
+    let role = require_server_role(
+        &state,
+        server_id,
+        &auth,
+        Some(ChatPermission::ManageMembers),
+    )
+    .await?;
+    if user_id == auth.user_id {
+        return Err(AppError::BadRequest(
+            "Use the leave endpoint to leave a server".to_string(),
+        ));
+    }
+    let target_role = server_role(&state, server_id, user_id)
+        .await?
+        .ok_or_else(|| {
+            AppError::NotFound(format!(
+                "User with ID {} is not a member of this server",
+                user_id
+            ))
+        })?;
+    if target_role >= role {
+        return Err(AppError::AuthError(
+            "Cannot remove a member with an equal or higher role".to_string(),
+        ));
+    }
+    remove_server_member(&state, server_id, user_id, auth.user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn leave_server_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(server_id): Path<ServerId>,
+) -> Result<StatusCode, AppError> {
+    require_server_role(&state, server_id, &auth, None).await?;
+    remove_server_member(&state, server_id, auth.user_id, auth.user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Changes a server member's role. Assigning `owner` transfers ownership and demotes the caller
+/// to admin.
+pub async fn update_server_member_role_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((server_id, user_id)): Path<(ServerId, UserId)>,
+    Json(payload): Json<UpdateMemberRole>,
+) -> Result<Json<Vec<ServerMember>>, AppError> {
+    require_server_role(&state, server_id, &auth, Some(ChatPermission::ManageRoles)).await?;
+    if user_id == auth.user_id {
+        return Err(AppError::BadRequest(
+            "Cannot change your own role".to_string(),
+        ));
+    }
+    if server_role(&state, server_id, user_id).await?.is_none() {
+        return Err(AppError::NotFound(format!(
+            "User with ID {} is not a member of this server",
+            user_id
+        )));
+    }
+    let mut tx = state.pool.begin().await?;
+    set_server_role(&mut tx, server_id, user_id, payload.role).await?;
+    if payload.role == ChatRole::Owner {
+        set_server_role(&mut tx, server_id, auth.user_id, ChatRole::Admin).await?;
+    }
+    tx.commit().await?;
+    notify_server_updated(&state, server_id, auth.user_id).await?;
+    Ok(Json(fetch_server_members(&state, server_id).await?))
+}
+
+pub async fn create_category_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(server_id): Path<ServerId>,
+    Json(payload): Json<CreateCategory>,
+) -> Result<Json<ServerTree>, AppError> {
+    let role = require_server_role(
+        &state,
+        server_id,
+        &auth,
+        Some(ChatPermission::ManageChannels),
+    )
+    .await?;
+    let name = validate_chat_name(&payload.name)?;
+    sqlx::query!(
+        r#"
+        INSERT INTO server_categories (server_id, name, position)
+        VALUES (?, ?, (SELECT COALESCE(MAX(position), -1) + 1 FROM server_categories WHERE server_id = ?))
+        "#,
+        server_id,
+        name,
+        server_id
+    )
+    .execute(&state.pool)
+    .await?;
+    notify_server_updated(&state, server_id, auth.user_id).await?;
+    Ok(Json(
+        fetch_server_tree(&state, server_id, auth.user_id, role).await?,
+    ))
+}
+
+async fn ensure_category_in_server(
+    state: &AppState,
+    server_id: ServerId,
+    category_id: CategoryId,
+) -> Result<(), AppError> {
+    let exists = sqlx::query_scalar!(
+        "SELECT 1 FROM server_categories WHERE id = ? AND server_id = ?",
+        category_id,
+        server_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .is_some();
+    if !exists {
+        return Err(AppError::NotFound(format!(
+            "Category with ID {} not found in this server",
+            category_id
+        )));
+    }
+    Ok(())
+}
+
+async fn fetch_channel(
+    state: &AppState,
+    server_id: ServerId,
+    chat_id: ChatId,
+    user_id: UserId,
+) -> Result<Channel, AppError> {
+    sqlx::query_as!(
+        Channel,
+        r#"
+        SELECT c.id as "id!", c.name, c.category_id, c.position as "position!", c.archived_at,
+               rs.last_read_message_id, COALESCE(rs.unread_count, 0) as "unread_count!: i64"
+        FROM chats c
+        LEFT JOIN chat_read_states rs ON rs.chat_id = c.id AND rs.user_id = ?
+        WHERE c.id = ? AND c.server_id = ?
+        "#,
+        user_id,
+        chat_id,
+        server_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(|| {
+        AppError::NotFound(format!(
+            "Channel with ID {} not found in this server",
+            chat_id
+        ))
+    })
+}
+
+pub async fn create_channel_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(server_id): Path<ServerId>,
+    Json(payload): Json<CreateChannel>,
+) -> Result<Json<Channel>, AppError> {
+    require_server_role(
+        &state,
+        server_id,
+        &auth,
+        Some(ChatPermission::ManageChannels),
+    )
+    .await?;
+    let name = validate_chat_name(&payload.name)?;
+    if let Some(category_id) = payload.category_id {
+        ensure_category_in_server(&state, server_id, category_id).await?;
+    }
+    let mut tx = state.pool.begin().await?;
+    let chat_id = create_channel(&mut tx, server_id, &name, payload.category_id).await?;
+    tx.commit().await?;
+    notify_server_updated(&state, server_id, auth.user_id).await?;
+    Ok(Json(
+        fetch_channel(&state, server_id, chat_id, auth.user_id).await?,
+    ))
+}
+
+/// Renames and/or archives a channel. Archived channels stay readable but reject new messages.
+pub async fn update_channel_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((server_id, chat_id)): Path<(ServerId, ChatId)>,
+    Json(payload): Json<UpdateChannel>,
+) -> Result<Json<Channel>, AppError> {
+    require_server_role(
+        &state,
+        server_id,
+        &auth,
+        Some(ChatPermission::ManageChannels),
+    )
+    .await?;
+    fetch_channel(&state, server_id, chat_id, auth.user_id).await?;
+    if let Some(name) = payload.name {
+        let name = validate_chat_name(&name)?;
+        sqlx::query!("UPDATE chats SET name = ? WHERE id = ?", name, chat_id)
+            .execute(&state.pool)
+            .await?;
+    }
+    match payload.archived {
+        Some(true) => {
+            let archived_at = chrono::Utc::now().to_rfc3339();
+            sqlx::query!(
+                "UPDATE chats SET archived_at = ? WHERE id = ? AND archived_at IS NULL",
+                archived_at,
+                chat_id
+            )
+            .execute(&state.pool)
+            .await?;
+        }
+        Some(false) => {
+            sqlx::query!("UPDATE chats SET archived_at = NULL WHERE id = ?", chat_id)
+                .execute(&state.pool)
+                .await?;
+        }
+        None => {}
+    }
+    notify_server_updated(&state, server_id, auth.user_id).await?;
+    Ok(Json(
+        fetch_channel(&state, server_id, chat_id, auth.user_id).await?,
+    ))
+}
+
+/// Applies a new category and channel ordering. Channels can be moved between categories;
+/// channels not mentioned in the layout keep their current placement.
+pub async fn update_server_layout_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(server_id): Path<ServerId>,
+    Json(payload): Json<ServerLayout>,
+) -> Result<Json<ServerTree>, AppError> {
+    let role = require_server_role(
+        &state,
+        server_id,
+        &auth,
+        Some(ChatPermission::ManageChannels),
+    )
+    .await?;
+    let mut placements: Vec<(ChatId, Option<CategoryId>)> = Vec::new();
+    for category in &payload.categories {
+        placements.extend(
+            category
+                .channel_ids
+                .iter()
+                .map(|id| (*id, Some(category.id))),
+        );
+    }
+    placements.extend(payload.channels.iter().map(|id| (*id, None)));
+    let mut tx = state.pool.begin().await?;
+    for (position, category) in payload.categories.iter().enumerate() {
+        let position = position as i64;
+        let affected = sqlx::query!(
+            "UPDATE server_categories SET position = ? WHERE id = ? AND server_id = ?",
+            position,
+            category.id,
+            server_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if affected == 0 {
+            return Err(AppError::NotFound(format!(
+                "Category with ID {} not found in this server",
+                category.id
+            )));
+        }
+    }
+    let mut positions = HashMap::new();
+    for (chat_id, category_id) in placements {
+        let position = positions.entry(category_id).or_insert(0i64);
+        let affected = sqlx::query!(
+            "UPDATE chats SET category_id = ?, position = ? WHERE id = ? AND server_id = ?",
+            category_id,
+            *position,
+            chat_id,
+            server_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if affected == 0 {
+            return Err(AppError::NotFound(format!(
+                "Channel with ID {} not found in this server",
+                chat_id
+            )));
+        }
+        *position += 1;
+    }
+    tx.commit().await?;
+    notify_server_updated(&state, server_id, auth.user_id).await?;
+    Ok(Json(
+        fetch_server_tree(&state, server_id, auth.user_id, role).await?,
+    ))
+}
+
+const MIN_EMOJI_NAME_LENGTH: usize = 2;
+const MAX_EMOJI_NAME_LENGTH: usize = 32;
+
+/// Custom emoji names are written as `:name:` by clients, so they are limited to lowercase
+/// letters, digits and underscores.
+fn validate_emoji_name(name: &str) -> Result<String, AppError> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid_chars || !(MIN_EMOJI_NAME_LENGTH..=MAX_EMOJI_NAME_LENGTH).contains(&name.len()) {
+        return Err(AppError::BadRequest(format!(
+            "Emoji name must be {} to {} lowercase letters, digits or underscores",
+            MIN_EMOJI_NAME_LENGTH, MAX_EMOJI_NAME_LENGTH
+        )));
+    }
+    Ok(name.to_string())
+}
+
+async fn fetch_custom_emoji(
+    state: &AppState,
+    server_id: ServerId,
+) -> Result<Vec<CustomEmoji>, AppError> {
+    Ok(sqlx::query_as!(
+        CustomEmoji,
+        r#"
+        SELECT e.id as "id!", e.server_id, e.name, f.url, e.created_by, e.created_at
+        FROM custom_emoji e
+        JOIN files f ON f.id = e.file_id
+        WHERE e.server_id = ?
+        ORDER BY e.name
+        "#,
+        server_id
+    )
+    .fetch_all(&state.pool)
+    .await?)
+}
+
+pub async fn list_custom_emoji_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(server_id): Path<ServerId>,
+) -> Result<Json<Vec<CustomEmoji>>, AppError> {
+    require_server_role(&state, server_id, &auth, None).await?;
+    Ok(Json(fetch_custom_emoji(&state, server_id).await?))
+}
+
+pub async fn create_custom_emoji_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(server_id): Path<ServerId>,
+    Json(payload): Json<CreateCustomEmoji>,
+) -> Result<Json<CustomEmoji>, AppError> {
+    require_server_role(&state, server_id, &auth, Some(ChatPermission::ManageEmoji)).await?;
+    let name = validate_emoji_name(&payload.name)?;
+    let image = payload.image;
+    if image.r#type != FileType::Picture {
+        return Err(AppError::BadRequest(
+            "Custom emoji must be a picture".to_string(),
+        ));
+    }
+    let created_at = chrono::Utc::now().to_rfc3339();
+    let mut tx = state.pool.begin().await?;
+    let file_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO files (type, url, filename, mime_type, size_bytes)
+        VALUES (?, ?, ?, ?, ?) RETURNING id
+        "#,
+        image.r#type,
+        image.url,
+        image.filename,
+        image.mime_type,
+        image.size_bytes
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO custom_emoji (server_id, name, file_id, created_by, created_at)
+        VALUES (?, ?, ?, ?, ?) RETURNING id
+        "#,
+        server_id,
+        name,
+        file_id,
+        auth.user_id,
+        created_at
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::Database(db) if db.is_unique_violation() => AppError::Conflict(format!(
+            "An emoji named {} already exists in this server",
+            name
+        )),
+        e => e.into(),
+    })?;
+    tx.commit().await?;
+    notify_server_updated(&state, server_id, auth.user_id).await?;
+    Ok(Json(CustomEmoji {
+        id,
+        server_id,
+        name,
+        url: image.url,
+        created_by: Some(auth.user_id),
+        created_at,
+    }))
+}
+
+/// Deletes a custom emoji together with all reactions made with it.
+pub async fn delete_custom_emoji_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((server_id, emoji_id)): Path<(ServerId, CustomEmojiId)>,
+) -> Result<StatusCode, AppError> {
+    require_server_role(&state, server_id, &auth, Some(ChatPermission::ManageEmoji)).await?;
+    let deleted = sqlx::query!(
+        "DELETE FROM custom_emoji WHERE id = ? AND server_id = ?",
+        emoji_id,
+        server_id
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected();
+    if deleted == 0 {
+        return Err(AppError::NotFound(format!(
+            "Emoji with ID {} not found in this server",
+            emoji_id
+        )));
+    }
+    notify_server_updated(&state, server_id, auth.user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn process_message(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    payload: WsMessageIn,
+) -> Result<Message, AppError> {
+    if let Some(nonce) = &payload.client_nonce {
+        if nonce.is_empty() || nonce.len() > MAX_CLIENT_NONCE_LENGTH {
+            return Err(AppError::BadRequest(format!(
+                "client_nonce must be between 1 and {} characters",
+                MAX_CLIENT_NONCE_LENGTH
+            )));
+        }
+        if let Some(existing) = find_message_by_nonce(state, auth.user_id, nonce).await? {
+            return ensure_same_chat(existing, payload.chat_id);
+        }
+    }
+    let has_content = payload
+        .content
+        .as_ref()
+        .map(|s| !s.trim().is_empty())
+        .unwrap_or(false);
+    let files_in = payload.files.unwrap_or_default();
+    let has_files = !files_in.is_empty();
+    if !has_content && !has_files {
+        return Err(AppError::BadRequest(
+            "Message must have text or at least one file".to_string(),
+        ));
+    }
+    if files_in.len() > 10 {
+        return Err(AppError::BadRequest(
+            "Maximum 10 files allowed per message".to_string(),
+        ));
+    }
+    for file in &files_in {
+        if file.size_bytes > 10 * 1024 * 1024 {
+            return Err(AppError::BadRequest(format!(
+                "File {} exceeds 10MB limit",
+                file.filename
+            )));
+        }
+    }
+    if !is_participant(state, payload.chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to send to this chat".to_string(),
+        ));
+    }
+    ensure_not_archived(state, payload.chat_id).await?;
+    let reply_preview = match payload.reply_to {
+        Some(reply_to) => {
+            let preview = fetch_message_preview(state, payload.chat_id, reply_to)
+                .await?
+                .ok_or_else(|| {
+                    AppError::BadRequest("reply_to must be a message in the same chat".to_string())
+                })?;
+            if preview.deleted {
+                return Err(AppError::BadRequest(
+                    "Cannot reply to a deleted message".to_string(),
+                ));
+            }
+            Some(preview)
+        }
+        None => None,
+    };
+    if let Some(root_id) = payload.thread_root_id {
+        ensure_thread_root(state, payload.chat_id, root_id).await?;
+    }
+    let timestamp = chrono::Utc::now().to_rfc3339();
+    let mut tx = state.pool.begin().await?;
+    let inserted = sqlx::query_scalar!(
+        r#"
+        INSERT INTO messages (chat_id, sender_id, content, timestamp, client_nonce, reply_to, thread_root_id)
+        VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING id
+        "#,
+        payload.chat_id,
+        auth.user_id,
+        payload.content,
+        timestamp,
+        payload.client_nonce,
+        payload.reply_to,
+        payload.thread_root_id
+    )
+    .fetch_one(&mut *tx)
+    .await;
+    let message_id = match (inserted, &payload.client_nonce) {
+        (Ok(message_id), _) => message_id,
+        // A concurrent retry with the same nonce won the race
+        (Err(sqlx::Error::Database(e)), Some(nonce)) if e.is_unique_violation() => {
+            drop(tx);
+            let existing = find_message_by_nonce(state, auth.user_id, nonce)
+                .await?
+                .ok_or_else(|| {
+                    AppError::InternalServerError("Duplicate message not found".to_string())
+                })?;
+            return ensure_same_chat(existing, payload.chat_id);
+        }
+        (Err(e), _) => return Err(e.into()),
+    };
+    let mut db_files = Vec::new();
+    for file_in in files_in {
+        let file_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO files (type, url, filename, mime_type, size_bytes)
+            VALUES (?, ?, ?, ?, ?) RETURNING id
+            "#,
+            file_in.r#type,
+            file_in.url,
+            file_in.filename,
+            file_in.mime_type,
+            file_in.size_bytes
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO message_files (message_id, file_id) VALUES (?, ?)",
+            message_id,
+            file_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        db_files.push(crate::models::MediaAsset {
+            id: file_id,
+            r#type: file_in.r#type,
+            url: file_in.url,
+            filename: file_in.filename,
+            mime_type: file_in.mime_type,
+            size_bytes: file_in.size_bytes,
+            created_at: timestamp.clone(),
+        });
+    }
+    // The sender has read everything up to their own message
+    sqlx::query!(
+        "UPDATE chat_participants SET last_read_message_id = ? WHERE chat_id = ? AND user_id = ?",
+        message_id,
+        payload.chat_id,
+        auth.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    let msg = Message {
+        id: message_id,
+        chat_id: payload.chat_id,
+        sender_id: auth.user_id,
+        content: payload.content,
+        timestamp,
+        edited_at: None,
+        deleted_at: None,
+        reply_to: payload.reply_to,
+        thread_root_id: payload.thread_root_id,
+        thread_reply_count: 0,
+        thread_last_reply_at: None,
+        forwarded: false,
+        forwarded_from_sender_id: None,
+        forwarded_from_chat_id: None,
+        files: db_files,
+        reply_preview,
+        reactions: None,
+        status: None,
+    };
+    match payload.thread_root_id {
+        Some(root_id) => {
+            broadcast_to_chat(state, payload.chat_id, &WsEvent::ThreadMessage(msg.clone())).await?;
+            notify_thread_updated(state, payload.chat_id, root_id).await?;
+        }
+        None => broadcast_to_chat(state, payload.chat_id, &WsEvent::Message(msg.clone())).await?,
+    }
+    end_typing(state, payload.chat_id, auth.user_id).await?;
+    Ok(msg)
+}
+
+const MAX_CLIENT_NONCE_LENGTH: usize = 128;
+
+/// Copies a message into another chat the caller participates in. Attachments are shared with
+/// the original rather than copied, and forwarding a forwarded message keeps the original
+/// sender and chat.
+async fn forward_message(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    chat_id: ChatId,
+    message_id: MessageId,
+    target_chat_id: ChatId,
+) -> Result<Message, AppError> {
+    if !is_participant(state, chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to view this chat".to_string(),
+        ));
+    }
+    if !is_participant(state, target_chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to send to this chat".to_string(),
+        ));
+    }
+    ensure_not_archived(state, target_chat_id).await?;
+    let original = fetch_message(state, chat_id, message_id).await?;
+    if original.deleted_at.is_some() {
+        return Err(AppError::BadRequest(
+            "Cannot forward a deleted message".to_string(),
+        ));
+    }
+    // Forwarding a forward keeps its source as recorded, even if that user or chat is gone
+    let (forwarded_from_sender_id, forwarded_from_chat_id) = if original.forwarded {
+        (
+            original.forwarded_from_sender_id,
+            original.forwarded_from_chat_id,
+        )
+    } else {
+        (Some(original.sender_id), Some(chat_id))
+    };
+    let timestamp = chrono::Utc::now().to_rfc3339();
+    let mut tx = state.pool.begin().await?;
+    let new_id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO messages (chat_id, sender_id, content, timestamp, forwarded, forwarded_from_sender_id, forwarded_from_chat_id)
+        VALUES (?, ?, ?, ?, TRUE, ?, ?) RETURNING id
+        "#,
+        target_chat_id,
+        auth.user_id,
+        original.content,
+        timestamp,
+        forwarded_from_sender_id,
+        forwarded_from_chat_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    for file in &original.files {
+        sqlx::query!(
+            "INSERT INTO message_files (message_id, file_id) VALUES (?, ?)",
+            new_id,
+            file.id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query!(
+        "UPDATE chat_participants SET last_read_message_id = ? WHERE chat_id = ? AND user_id = ?",
+        new_id,
+        target_chat_id,
+        auth.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    let msg = Message {
+        id: new_id,
+        chat_id: target_chat_id,
+        sender_id: auth.user_id,
+        timestamp,
+        edited_at: None,
+        reply_to: None,
+        thread_root_id: None,
+        thread_reply_count: 0,
+        thread_last_reply_at: None,
+        forwarded: true,
+        forwarded_from_sender_id,
+        forwarded_from_chat_id,
+        reply_preview: None,
+        reactions: None,
+        status: None,
+        ..original
+    };
+    // Only participants of the source chat are told which chat it was
+    let recipients = sqlx::query!(
+        r#"
+        SELECT cp.user_id,
+               EXISTS (
+                   SELECT 1 FROM chat_participants s WHERE s.chat_id = ? AND s.user_id = cp.user_id
+               ) as "in_source_chat!: bool"
+        FROM chat_participants cp
+        WHERE cp.chat_id = ?
+        "#,
+        forwarded_from_chat_id,
+        target_chat_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let (in_source_chat, others): (Vec<_>, Vec<_>) =
+        recipients.into_iter().partition(|r| r.in_source_chat);
+    let in_source_chat: Vec<UserId> = in_source_chat.into_iter().map(|r| r.user_id).collect();
+    let others: Vec<UserId> = others.into_iter().map(|r| r.user_id).collect();
+    publish(state, &in_source_chat, &WsEvent::Message(msg.clone())).await?;
+    let anonymous = Message {
+        forwarded_from_chat_id: None,
+        ..msg.clone()
+    };
+    publish(state, &others, &WsEvent::Message(anonymous)).await?;
+    Ok(msg)
+}
+
+pub async fn forward_message_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((chat_id, message_id)): Path<(ChatId, MessageId)>,
+    Json(payload): Json<ForwardMessage>,
+) -> Result<Json<Message>, AppError> {
+    let mut message =
+        forward_message(&state, &auth, chat_id, message_id, payload.target_chat_id).await?;
+    fill_for_viewer(&state, auth.user_id, std::slice::from_mut(&mut message)).await?;
+    Ok(Json(message))
+}
+
+/// Checks that a thread can be replied to: threads hang off a live message of the main stream.
+async fn ensure_thread_root(
+    state: &AppState,
+    chat_id: ChatId,
+    root_id: MessageId,
+) -> Result<(), AppError> {
+    let root = sqlx::query!(
+        "SELECT thread_root_id, deleted_at FROM messages WHERE id = ? AND chat_id = ?",
+        root_id,
+        chat_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(|| {
+        AppError::BadRequest("thread_root_id must be a message in the same chat".to_string())
+    })?;
+    if root.thread_root_id.is_some() {
+        return Err(AppError::BadRequest(
+            "Thread replies cannot have threads of their own".to_string(),
+        ));
+    }
+    if root.deleted_at.is_some() {
+        return Err(AppError::BadRequest(
+            "Cannot reply in the thread of a deleted message".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Sends the chat the new reply count and last reply time of a thread.
+async fn notify_thread_updated(
+    state: &AppState,
+    chat_id: ChatId,
+    root_id: MessageId,
+) -> Result<(), AppError> {
+    let summary = sqlx::query!(
+        r#"
+        SELECT COUNT(*) as "reply_count!: i64", MAX(timestamp) as "last_reply_at?: String"
+        FROM messages
+        WHERE thread_root_id = ? AND deleted_at IS NULL
+        "#,
+        root_id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+    broadcast_to_chat(
+        state,
+        chat_id,
+        &WsEvent::ThreadUpdated {
+            chat_id,
+            root_message_id: root_id,
+            reply_count: summary.reply_count,
+            last_reply_at: summary.last_reply_at,
+        },
+    )
+    .await
+}
+
+async fn find_message_by_nonce(
+    state: &AppState,
+    sender_id: UserId,
+    nonce: &str,
+) -> Result<Option<Message>, AppError> {
+    let row = sqlx::query!(
+        r#"SELECT id as "id!", chat_id FROM messages WHERE sender_id = ? AND client_nonce = ?"#,
+        sender_id,
+        nonce
+    )
+    .fetch_optional(&state.pool)
+    .await?;
+    match row {
+        Some(row) => Ok(Some(fetch_message(state, row.chat_id, row.id).await?)),
+        None => Ok(None),
+    }
+}
+
+/// A retried send must target the chat the nonce was first used in.
+fn ensure_same_chat(message: Message, chat_id: ChatId) -> Result<Message, AppError> {
+    if message.chat_id != chat_id {
+        return Err(AppError::Conflict(
+            "client_nonce was already used for a message in another chat".to_string(),
+        ));
+    }
+    Ok(message)
+}
+
+async fn ensure_not_archived(state: &AppState, chat_id: ChatId) -> Result<(), AppError> {
+    let is_archived = sqlx::query_scalar!(
+        "SELECT 1 FROM chats WHERE id = ? AND archived_at IS NOT NULL",
+        chat_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .is_some();
+    if is_archived {
+        return Err(AppError::BadRequest(
+            "Cannot write to an archived channel".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Fills in the files attached to the messages.
+async fn fill_message_files(state: &AppState, messages: &mut [Message]) -> Result<(), AppError> {
+    let message_ids: Vec<MessageId> = messages.iter().map(|m| m.id).collect();
+    let message_ids = serde_json::to_string(&message_ids).unwrap();
+    let rows = sqlx::query!(
+        r#"
+        SELECT mf.message_id, f.id as "id!", f.type as "type: crate::models::FileType", f.url as "url!", f.filename as "filename!", f.mime_type, f.size_bytes as "size_bytes!", f.created_at as "created_at!"
+        FROM files f
+        JOIN message_files mf ON f.id = mf.file_id
+        WHERE mf.message_id IN (SELECT value FROM json_each(?))
+        ORDER BY f.id
+        "#,
+        message_ids
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let mut files: HashMap<MessageId, Vec<MediaAsset>> = HashMap::new();
+    for row in rows {
+        files.entry(row.message_id).or_default().push(MediaAsset {
+            id: row.id,
+            r#type: row.r#type,
+            url: row.url,
+            filename: row.filename,
+            mime_type: row.mime_type,
+            size_bytes: row.size_bytes,
+            created_at: row.created_at,
+        });
+    }
+    for message in messages.iter_mut() {
+        message.files = files.remove(&message.id).unwrap_or_default();
+    }
+    Ok(())
+}
+
+/// Loads messages by id with the summary of their threads, in no particular order. Files,
+/// previews, statuses and reactions are left to the caller.
+async fn fetch_messages_by_id(
+    state: &AppState,
+    message_ids: &[MessageId],
+) -> Result<Vec<Message>, AppError> {
+    let message_ids = serde_json::to_string(message_ids).unwrap();
+    let rows = sqlx::query_as!(
+        MessageRow,
+        r#"
+        SELECT m.id as "id!", m.chat_id as "chat_id!", m.sender_id as "sender_id!", m.content,
+               m.timestamp as "timestamp!", m.edited_at, m.deleted_at, m.reply_to, m.thread_root_id,
+               COALESCE(t.reply_count, 0) as "thread_reply_count!: i64",
+               t.last_reply_at as "thread_last_reply_at?: String",
+               m.forwarded as "forwarded!: bool", m.forwarded_from_sender_id, m.forwarded_from_chat_id
+        FROM messages m
+        LEFT JOIN (
+            SELECT thread_root_id, COUNT(*) AS reply_count, MAX(timestamp) AS last_reply_at
+            FROM messages
+            WHERE thread_root_id IN (SELECT value FROM json_each(?1)) AND deleted_at IS NULL
+            GROUP BY thread_root_id
+        ) t ON t.thread_root_id = m.id
+        WHERE m.id IN (SELECT value FROM json_each(?1))
+        "#,
+        message_ids
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    Ok(rows.into_iter().map(Message::from).collect())
+}
+
+const SNIPPET_LENGTH: usize = 100;
+
+fn preview_snippet(content: Option<String>) -> Option<String> {
+    content.map(|content| content.chars().take(SNIPPET_LENGTH).collect())
+}
+
+/// Loads the previews of the messages that exist, keyed by id, along with their chat.
+async fn fetch_message_previews(
+    state: &AppState,
+    message_ids: &[MessageId],
+) -> Result<HashMap<MessageId, (ChatId, MessagePreview)>, AppError> {
+    let message_ids = serde_json::to_string(message_ids).unwrap();
+    let rows = sqlx::query!(
+        r#"
+        SELECT m.id as "id!", m.chat_id, m.sender_id, m.content, m.timestamp, m.deleted_at,
+               (
+                   SELECT f.type FROM message_files mf JOIN files f ON f.id = mf.file_id
+                   WHERE mf.message_id = m.id
+                   ORDER BY f.id LIMIT 1
+               ) as "attachment?: FileType"
+        FROM messages m
+        WHERE m.id IN (SELECT value FROM json_each(?))
+        "#,
+        message_ids
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let preview = MessagePreview {
+                id: row.id,
+                sender_id: row.sender_id,
+                snippet: preview_snippet(row.content),
+                timestamp: row.timestamp,
+                attachment: row.attachment,
+                deleted: row.deleted_at.is_some(),
+            };
+            (row.id, (row.chat_id, preview))
+        })
+        .collect())
+}
+
+/// Loads the preview of a message, or None if it is not in the given chat.
+async fn fetch_message_preview(
+    state: &AppState,
+    chat_id: ChatId,
+    message_id: MessageId,
+) -> Result<Option<MessagePreview>, AppError> {
+    let mut previews = fetch_message_previews(state, &[message_id]).await?;
+    Ok(previews
+        .remove(&message_id)
+        .filter(|(preview_chat_id, _)| *preview_chat_id == chat_id)
+        .map(|(_, preview)| preview))
+}
+
+/// Embeds the preview of the quoted message into replies.
+async fn fill_reply_previews(state: &AppState, messages: &mut [Message]) -> Result<(), AppError> {
+    let quoted: Vec<MessageId> = messages.iter().filter_map(|m| m.reply_to).collect();
+    if quoted.is_empty() {
+        return Ok(());
+    }
+    let previews = fetch_message_previews(state, &quoted).await?;
+    for message in messages {
+        message.reply_preview = message
+            .reply_to
+            .and_then(|reply_to| previews.get(&reply_to))
+            .filter(|(chat_id, _)| *chat_id == message.chat_id)
+            .map(|(_, preview)| preview.clone());
+    }
+    Ok(())
+}
+
+async fn fetch_message(
+    state: &AppState,
+    chat_id: ChatId,
+    message_id: MessageId,
+) -> Result<Message, AppError> {
+    let mut message = fetch_messages_by_id(state, &[message_id])
+        .await?
+        .into_iter()
+        .find(|message| message.chat_id == chat_id)
+        .ok_or_else(|| AppError::NotFound(format!("Message with ID {} not found", message_id)))?;
+    fill_message_files(state, std::slice::from_mut(&mut message)).await?;
+    fill_reply_previews(state, std::slice::from_mut(&mut message)).await?;
+    Ok(message)
+}
+
+/// Replaces the content of one of the caller's messages, keeping the previous version in
+/// `message_edits`, and notifies the chat.
+async fn edit_message(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    chat_id: ChatId,
+    message_id: MessageId,
+    content: String,
+) -> Result<Message, AppError> {
+    if content.trim().is_empty() {
+        return Err(AppError::BadRequest(
+            "Message content must not be empty".to_string(),
+        ));
+    }
+    if !is_participant(state, chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to edit in this chat".to_string(),
+        ));
+    }
+    ensure_not_archived(state, chat_id).await?;
+    let message = fetch_message(state, chat_id, message_id).await?;
+    if message.sender_id != auth.user_id {
+        return Err(AppError::AuthError(
+            "Only the sender can edit a message".to_string(),
+        ));
+    }
+    if message.deleted_at.is_some() {
+        return Err(AppError::BadRequest(
+            "Cannot edit a deleted message".to_string(),
+        ));
+    }
+    // The text stays attributed to the original sender
+    if message.forwarded {
+        return Err(AppError::BadRequest(
+            "Cannot edit a forwarded message".to_string(),
+        ));
+    }
+    if message.content.as_deref() == Some(content.as_str()) {
+        return Ok(message);
+    }
+    let edited_at = chrono::Utc::now().to_rfc3339();
+    let mut tx = state.pool.begin().await?;
+    sqlx::query!(
+        "INSERT INTO message_edits (message_id, content, edited_at) VALUES (?, ?, ?)",
+        message_id,
+        message.content,
+        edited_at
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE messages SET content = ?, edited_at = ? WHERE id = ?",
+        content,
+        edited_at,
+        message_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    let message = Message {
+        content: Some(content),
+        edited_at: Some(edited_at),
+        ..message
+    };
+    broadcast_to_chat(state, chat_id, &WsEvent::MessageEdited(message.clone())).await?;
+    Ok(message)
+}
+
+pub async fn edit_message_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((chat_id, message_id)): Path<(ChatId, MessageId)>,
+    Json(payload): Json<EditMessage>,
+) -> Result<Json<Message>, AppError> {
+    let mut message = edit_message(&state, &auth, chat_id, message_id, payload.content).await?;
+    fill_for_viewer(&state, auth.user_id, std::slice::from_mut(&mut message)).await?;
+    Ok(Json(message))
+}
+
+/// Deletes a message for the caller only, or for everyone. Deleting for everyone replaces the
+/// message with a tombstone; senders may do so within the configured window, while members
+/// allowed to delete others' messages may do so at any time.
+pub async fn delete_message_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((chat_id, message_id)): Path<(ChatId, MessageId)>,
+    Query(query): Query<DeleteMessageQuery>,
+) -> Result<StatusCode, AppError> {
+    let role = participant_role(&state, chat_id, auth.user_id)
+        .await?
+        .ok_or_else(|| AppError::AuthError("Not authorized to view this chat".to_string()))?;
+    let message = fetch_message(&state, chat_id, message_id).await?;
+    let now = chrono::Utc::now();
+    if !query.for_everyone {
+        let hidden_at = now.to_rfc3339();
+        sqlx::query!(
+            "INSERT OR IGNORE INTO hidden_messages (message_id, user_id, hidden_at) VALUES (?, ?, ?)",
+            message_id,
+            auth.user_id,
+            hidden_at
+        )
+        .execute(&state.pool)
+        .await?;
+        send_to_user(
+            &state,
+            auth.user_id,
+            &WsEvent::MessageDeleted {
+                chat_id,
+                message_id,
+                deleted_by: auth.user_id,
+                for_everyone: false,
+            },
+        )
+        .await?;
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    if message.deleted_at.is_some() {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    let chat = fetch_chat(&state, chat_id).await?;
+    if message.sender_id != auth.user_id {
+        require_permission(&chat, role, ChatPermission::DeleteOthersMessages)?;
+    } else if !role.allows(&chat.chat_type, ChatPermission::DeleteOthersMessages) {
+        let sent_at = chrono::DateTime::parse_from_rfc3339(&message.timestamp).map_err(|_| {
+            AppError::InternalServerError("Stored message timestamp is invalid".to_string())
+        })?;
+        let window = chrono::Duration::seconds(state.delete_for_everyone_window_secs);
+        if now.signed_duration_since(sent_at) > window {
+            return Err(AppError::BadRequest(
+                "Message is too old to be deleted for everyone".to_string(),
+            ));
+        }
+    }
+    let deleted_at = now.to_rfc3339();
+    let mut tx = state.pool.begin().await?;
+    sqlx::query!(
+        "UPDATE messages SET content = NULL, deleted_at = ? WHERE id = ?",
+        deleted_at,
+        message_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM message_files WHERE message_id = ?", message_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM message_edits WHERE message_id = ?", message_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "DELETE FROM message_reactions WHERE message_id = ?",
+        message_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM pinned_messages WHERE message_id = ?",
+        message_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    broadcast_to_chat(
+        &state,
+        chat_id,
+        &WsEvent::MessageDeleted {
+            chat_id,
+            message_id,
+            deleted_by: auth.user_id,
+            for_everyone: true,
+        },
+    )
+    .await?;
+    if let Some(root_id) = message.thread_root_id {
+        notify_thread_updated(&state, chat_id, root_id).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Fails unless the caller may pin and unpin messages in the chat.
+async fn require_pin_permission(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    chat_id: ChatId,
+) -> Result<(), AppError> {
+    let role = participant_role(state, chat_id, auth.user_id)
+        .await?
+        .ok_or_else(|| AppError::AuthError("Not authorized to view this chat".to_string()))?;
+    let chat = fetch_chat(state, chat_id).await?;
+    require_permission(&chat, role, ChatPermission::PinMessages)
+}
+
+pub async fn pin_message_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((chat_id, message_id)): Path<(ChatId, MessageId)>,
+) -> Result<StatusCode, AppError> {
+    require_pin_permission(&state, &auth, chat_id).await?;
+    ensure_not_archived(&state, chat_id).await?;
+    let message = fetch_message(&state, chat_id, message_id).await?;
+    if message.deleted_at.is_some() {
+        return Err(AppError::BadRequest(
+            "Cannot pin a deleted message".to_string(),
+        ));
+    }
+    let pinned_at = chrono::Utc::now().to_rfc3339();
+    let inserted = sqlx::query!(
+        r#"
+        INSERT OR IGNORE INTO pinned_messages (message_id, chat_id, pinned_by, pinned_at)
+        VALUES (?, ?, ?, ?)
+        "#,
+        message_id,
+        chat_id,
+        auth.user_id,
+        pinned_at
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected();
+    if inserted > 0 {
+        let event = WsEvent::MessagePinned {
+            chat_id,
+            message_id,
+            pinned_by: auth.user_id,
+        };
+        broadcast_to_chat(&state, chat_id, &event).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn unpin_message_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((chat_id, message_id)): Path<(ChatId, MessageId)>,
+) -> Result<StatusCode, AppError> {
+    require_pin_permission(&state, &auth, chat_id).await?;
+    ensure_not_archived(&state, chat_id).await?;
+    let removed = sqlx::query!(
+        "DELETE FROM pinned_messages WHERE message_id = ? AND chat_id = ?",
+        message_id,
+        chat_id
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected();
+    if removed == 0 {
+        return Err(AppError::NotFound(format!(
+            "Message with ID {} is not pinned",
+            message_id
+        )));
+    }
+    let event = WsEvent::MessageUnpinned {
+        chat_id,
+        message_id,
+        unpinned_by: auth.user_id,
+    };
+    broadcast_to_chat(&state, chat_id, &event).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lists the messages pinned in a chat, most recently pinned first.
+pub async fn list_pins_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(chat_id): Path<ChatId>,
+) -> Result<Json<Vec<PinnedMessage>>, AppError> {
+    if !is_participant(&state, chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to view this chat".to_string(),
+        ));
+    }
+    // Pins of messages the caller deleted for themselves are left out, as in the history
+    let rows = sqlx::query!(
+        r#"
+        SELECT p.message_id as "message_id!", p.pinned_by, p.pinned_at
+        FROM pinned_messages p
+        WHERE p.chat_id = ?
+          AND NOT EXISTS (
+            SELECT 1 FROM hidden_messages h WHERE h.message_id = p.message_id AND h.user_id = ?
+          )
+        ORDER BY p.pinned_at DESC, p.message_id DESC
+        "#,
+        chat_id,
+        auth.user_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let message_ids: Vec<MessageId> = rows.iter().map(|row| row.message_id).collect();
+    let mut messages = fetch_messages_by_id(&state, &message_ids).await?;
+    fill_message_files(&state, &mut messages).await?;
+    fill_reply_previews(&state, &mut messages).await?;
+    fill_for_viewer(&state, auth.user_id, &mut messages).await?;
+    let mut messages: HashMap<MessageId, Message> =
+        messages.into_iter().map(|m| (m.id, m)).collect();
+    let pins = rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(PinnedMessage {
+                message: messages.remove(&row.message_id)?,
+                pinned_by: row.pinned_by,
+                pinned_at: row.pinned_at,
+            })
+        })
+        .collect();
+    Ok(Json(pins))
+}
+
+/// Checks the shape of a reaction. Unicode emoji must be a single known emoji and are stored in
+/// their fully qualified form, so that e.g. "❤" and "❤️" count as the same reaction.
+fn validate_reaction(reaction: Reaction) -> Result<Reaction, AppError> {
+    match (&reaction.emoji, reaction.custom_emoji_id) {
+        (Some(emoji), None) => match emojis::get(emoji) {
+            Some(known) => Ok(Reaction {
+                emoji: Some(known.as_str().to_string()),
+                custom_emoji_id: None,
+            }),
+            None => Err(AppError::BadRequest(format!(
+                "'{}' is not a single unicode emoji",
+                emoji
+            ))),
+        },
+        // Whether the emoji may be used in the chat is checked when adding the reaction
+        (None, Some(_)) => Ok(reaction),
+        _ => Err(AppError::BadRequest(
+            "Exactly one of emoji and custom_emoji_id must be given".to_string(),
+        )),
+    }
+}
+
+/// Custom emoji belong to a server and can only be used in its channels.
+async fn ensure_emoji_usable_in_chat(
+    state: &AppState,
+    chat_id: ChatId,
+    emoji_id: CustomEmojiId,
+) -> Result<(), AppError> {
+    let usable = sqlx::query_scalar!(
+        r#"
+        SELECT 1 as "usable!: i64"
+        FROM custom_emoji e
+        JOIN chats c ON c.server_id = e.server_id
+        WHERE e.id = ? AND c.id = ?
+        "#,
+        emoji_id,
+        chat_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .is_some();
+    if !usable {
+        return Err(AppError::BadRequest(format!(
+            "Emoji with ID {} cannot be used in this chat",
+            emoji_id
+        )));
+    }
+    Ok(())
+}
+
+/// Adds the caller's reaction to a message and notifies the chat. Adding a reaction twice is
+/// a no-op.
+async fn add_reaction(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    chat_id: ChatId,
+    message_id: MessageId,
+    reaction: Reaction,
+) -> Result<(), AppError> {
+    let reaction = validate_reaction(reaction)?;
+    if !is_participant(state, chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to react in this chat".to_string(),
+        ));
+    }
+    ensure_not_archived(state, chat_id).await?;
+    let message = fetch_message(state, chat_id, message_id).await?;
+    if message.deleted_at.is_some() {
+        return Err(AppError::BadRequest(
+            "Cannot react to a deleted message".to_string(),
+        ));
+    }
+    if let Some(emoji_id) = reaction.custom_emoji_id {
+        ensure_emoji_usable_in_chat(state, chat_id, emoji_id).await?;
+    }
+    let created_at = chrono::Utc::now().to_rfc3339();
+    let inserted = sqlx::query!(
+        r#"
+        INSERT OR IGNORE INTO message_reactions (message_id, user_id, emoji, custom_emoji_id, created_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#,
+        message_id,
+        auth.user_id,
+        reaction.emoji,
+        reaction.custom_emoji_id,
+        created_at
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected();
+    if inserted > 0 {
+        let event = WsEvent::ReactionAdded {
+            chat_id,
+            message_id,
+            user_id: auth.user_id,
+            reaction,
+        };
+        broadcast_to_chat(state, chat_id, &event).await?;
+    }
+    Ok(())
+}
+
+/// Removes the caller's reaction from a message and notifies the chat.
+async fn remove_reaction(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    chat_id: ChatId,
+    message_id: MessageId,
+    reaction: Reaction,
+) -> Result<(), AppError> {
+    let reaction = validate_reaction(reaction)?;
+    if !is_participant(state, chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to react in this chat".to_string(),
+        ));
+    }
+    ensure_not_archived(state, chat_id).await?;
+    fetch_message(state, chat_id, message_id).await?;
+    let removed = sqlx::query!(
+        r#"
+        DELETE FROM message_reactions
+        WHERE message_id = ? AND user_id = ? AND emoji IS ? AND custom_emoji_id IS ?
+        "#,
+        message_id,
+        auth.user_id,
+        reaction.emoji,
+        reaction.custom_emoji_id
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected();
+    if removed > 0 {
+        let event = WsEvent::ReactionRemoved {
+            chat_id,
+            message_id,
+            user_id: auth.user_id,
+            reaction,
+        };
+        broadcast_to_chat(state, chat_id, &event).await?;
+    }
+    Ok(())
+}
+
+pub async fn add_reaction_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((chat_id, message_id)): Path<(ChatId, MessageId)>,
+    Json(payload): Json<Reaction>,
+) -> Result<StatusCode, AppError> {
+    add_reaction(&state, &auth, chat_id, message_id, payload).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn remove_reaction_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((chat_id, message_id)): Path<(ChatId, MessageId)>,
+    Query(query): Query<Reaction>,
+) -> Result<StatusCode, AppError> {
+    remove_reaction(&state, &auth, chat_id, message_id, query).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_message_edits_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((chat_id, message_id)): Path<(ChatId, MessageId)>,
+) -> Result<Json<Vec<MessageEdit>>, AppError> {
+    if !is_participant(&state, chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to view this chat".to_string(),
+        ));
+    }
+    fetch_message(&state, chat_id, message_id).await?;
+    let edits = sqlx::query_as!(
+        MessageEdit,
+        r#"
+        SELECT id as "id!", message_id as "message_id!", content, edited_at as "edited_at!"
+        FROM message_edits
+        WHERE message_id = ?
+        ORDER BY id
+        "#,
+        message_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    Ok(Json(edits))
+}
+
+/// Number of events kept per user for replay after a reconnect.
+const MAX_LOGGED_EVENTS_PER_USER: EventSeq = 1000;
+
+/// Queues an event on every live connection of the user. Returns whether any connection
+/// accepted it; a connection with a full queue only gets the event on its catch-up replay.
+fn deliver(state: &AppState, user_id: UserId, event: &OutboundEvent) -> bool {
+    let Some(connections) = state.active_connections.get(&user_id) else {
+        return false;
+    };
+    let mut queued = false;
+    for connection in connections.values() {
+        match connection.sender.try_send(event.clone()) {
+            Ok(()) => queued = true,
+            Err(TrySendError::Full(_)) => {
+                // The connection catches up from the event log once it has drained its queue
+                connection.lagged.notify_one();
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+    queued
+}
+
+/// Records that each of `user_ids` received the messages, and tells the sender of each message
+/// that has now reached all of its recipients. Users' own messages are skipped.
+async fn record_deliveries(
+    state: &AppState,
+    user_ids: &[UserId],
+    message_ids: &[MessageId],
+) -> Result<(), AppError> {
+    let delivered_at = chrono::Utc::now().to_rfc3339();
+    let user_ids = serde_json::to_string(user_ids).unwrap();
+    let message_ids = serde_json::to_string(message_ids).unwrap();
+    let mut inserted = sqlx::query_scalar!(
+        r#"
+        INSERT OR IGNORE INTO message_deliveries (message_id, user_id, delivered_at)
+        SELECT m.id, u.value, ?
+        FROM messages m
+        JOIN json_each(?) u ON u.value != m.sender_id
+        WHERE m.id IN (SELECT value FROM json_each(?))
+        RETURNING message_id
+        "#,
+        delivered_at,
+        user_ids,
+        message_ids
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    if inserted.is_empty() {
+        return Ok(());
+    }
+    inserted.sort_unstable();
+    inserted.dedup();
+    let inserted = serde_json::to_string(&inserted).unwrap();
+    let completed = sqlx::query!(
+        r#"
+        SELECT message_id as "message_id!", chat_id as "chat_id!", sender_id as "sender_id!"
+        FROM message_statuses
+        WHERE message_id IN (SELECT value FROM json_each(?)) AND status = 'delivered'
+        ORDER BY message_id
+        "#,
+        inserted
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    for row in completed {
+        let event = WsEvent::MessageDelivered {
+            chat_id: row.chat_id,
+            message_id: row.message_id,
+        };
+        // Boxed: publishing a message records deliveries, which publishes this event
+        Box::pin(send_to_user(state, row.sender_id, &event)).await?;
+    }
+    Ok(())
+}
+
+/// The ids of the messages carried by replayed event frames.
+fn replayed_message_ids(frames: &[String]) -> Vec<MessageId> {
+    frames
+        .iter()
+        .filter_map(|frame| {
+            let frame: serde_json::Value = serde_json::from_str(frame).ok()?;
+            match frame["type"].as_str()? {
+                "message" | "thread_message" => frame["payload"]["id"].as_i64(),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Records the deliveries of messages replayed to a connection once they have been sent.
+async fn record_replayed_deliveries(state: &AppState, user_id: UserId, message_ids: &[MessageId]) {
+    if message_ids.is_empty() {
+        return;
+    }
+    if let Err(e) = record_deliveries(state, &[user_id], message_ids).await {
+        tracing::error!("Failed to record message deliveries: {:?}", e);
+    }
+}
+
+/// Fills in the parts of the messages that depend on who is looking at them.
+async fn fill_for_viewer(
+    state: &AppState,
+    viewer: UserId,
+    messages: &mut [Message],
+) -> Result<(), AppError> {
+    fill_message_statuses(state, viewer, messages).await?;
+    fill_message_reactions(state, viewer, messages).await?;
+    hide_foreign_forward_sources(state, viewer, messages).await
+}
+
+/// Clears the source chat of forwarded messages from chats `viewer` does not take part in.
+async fn hide_foreign_forward_sources(
+    state: &AppState,
+    viewer: UserId,
+    messages: &mut [Message],
+) -> Result<(), AppError> {
+    let source_chats: Vec<ChatId> = messages
+        .iter()
+        .filter_map(|m| m.forwarded_from_chat_id)
+        .collect();
+    if source_chats.is_empty() {
+        return Ok(());
+    }
+    let source_chats = serde_json::to_string(&source_chats).unwrap();
+    let joined: HashSet<ChatId> = sqlx::query_scalar!(
+        r#"
+        SELECT chat_id FROM chat_participants
+        WHERE user_id = ? AND chat_id IN (SELECT value FROM json_each(?))
+        "#,
+        viewer,
+        source_chats
+    )
+    .fetch_all(&state.pool)
+    .await?
+    .into_iter()
+    .collect();
+    for message in messages.iter_mut() {
+        if message
+            .forwarded_from_chat_id
+            .is_some_and(|chat_id| !joined.contains(&chat_id))
+        {
+            message.forwarded_from_chat_id = None;
+        }
+    }
+    Ok(())
+}
+
+/// Fills in the delivery status of the messages `viewer` sent.
+async fn fill_message_statuses(
+    state: &AppState,
+    viewer: UserId,
+    messages: &mut [Message],
+) -> Result<(), AppError> {
+    let own: Vec<MessageId> = messages
+        .iter()
+        .filter(|m| m.sender_id == viewer)
+        .map(|m| m.id)
+        .collect();
+    if own.is_empty() {
+        return Ok(());
+    }
+    let own = serde_json::to_string(&own).unwrap();
+    let statuses: HashMap<MessageId, MessageStatus> = sqlx::query!(
+        r#"
+        SELECT message_id as "message_id!", status as "status!: MessageStatus"
+        FROM message_statuses
+        WHERE message_id IN (SELECT value FROM json_each(?))
+        "#,
+        own
+    )
+    .fetch_all(&state.pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.message_id, row.status))
+    .collect();
+    for message in messages.iter_mut().filter(|m| m.sender_id == viewer) {
+        message.status = statuses.get(&message.id).copied();
+    }
+    Ok(())
+}
+
+/// Fills in the reactions on the messages, with whether `viewer` is among the reacting users.
+async fn fill_message_reactions(
+    state: &AppState,
+    viewer: UserId,
+    messages: &mut [Message],
+) -> Result<(), AppError> {
+    let message_ids: Vec<MessageId> = messages.iter().map(|m| m.id).collect();
+    let message_ids = serde_json::to_string(&message_ids).unwrap();
+    let rows = sqlx::query!(
+        r#"
+        SELECT message_id, emoji, custom_emoji_id, COUNT(*) as "count!: i64", MAX(user_id = ?) as "reacted_by_me!: bool"
+        FROM message_reactions
+        WHERE message_id IN (SELECT value FROM json_each(?))
+        GROUP BY message_id, emoji, custom_emoji_id
+        ORDER BY MIN(created_at)
+        "#,
+        viewer,
+        message_ids
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let mut reactions: HashMap<MessageId, Vec<ReactionSummary>> = HashMap::new();
+    for row in rows {
+        reactions
+            .entry(row.message_id)
+            .or_default()
+            .push(ReactionSummary {
+                reaction: Reaction {
+                    emoji: row.emoji,
+                    custom_emoji_id: row.custom_emoji_id,
+                },
+                count: row.count,
+                reacted_by_me: row.reacted_by_me,
+            });
+    }
+    for message in messages.iter_mut() {
+        message.reactions = Some(reactions.remove(&message.id).unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Closes the user's live connections matching `filter`, returning how many were closed.
+fn close_connections(
+    state: &AppState,
+    user_id: UserId,
+    filter: impl Fn(&Connection) -> bool,
+) -> usize {
+    let Some(connections) = state.active_connections.get(&user_id) else {
+        return 0;
+    };
+    let mut closed = 0;
+    for connection in connections.values().filter(|c| filter(&c.info)) {
+        connection.close.notify_one();
+        closed += 1;
+    }
+    closed
+}
+
+/// Records `event` in each recipient's event log under their next sequence number, then pushes
+/// it to their live connections. All recipients are written in one transaction.
+async fn publish(state: &AppState, recipients: &[UserId], event: &WsEvent) -> Result<(), AppError> {
+    let created_at = chrono::Utc::now().to_rfc3339();
+    let recipient_ids = serde_json::to_string(recipients).unwrap();
+    // Queued before the next publish can take a sequence number, so no connection sees a
+    // later seq ahead of an earlier one
+    let ordering = state.publish_lock.lock().await;
+    let mut tx = state.pool.begin().await?;
+    let seqs = sqlx::query!(
+        r#"
+        UPDATE users SET event_seq = event_seq + 1
+        WHERE id IN (SELECT value FROM json_each(?))
+        RETURNING id as "id!", event_seq as "event_seq!"
+        "#,
+        recipient_ids
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let frames: Vec<(UserId, OutboundEvent)> = seqs
+        .into_iter()
+        .map(|row| {
+            let json = serde_json::to_string(&SequencedEvent {
+                seq: row.event_seq,
+                event,
+            })
+            .unwrap();
+            let frame = OutboundEvent {
+                seq: Some(row.event_seq),
+                json,
+            };
+            (row.id, frame)
+        })
+        .collect();
+    let rows = serde_json::Value::Array(
+        frames
+            .iter()
+            .map(|(user_id, frame)| {
+                serde_json::json!({ "user_id": user_id, "seq": frame.seq, "frame": frame.json })
+            })
+            .collect(),
+    )
+    .to_string();
+    sqlx::query!(
+        r#"
+        INSERT INTO user_events (user_id, seq, frame, created_at)
+        SELECT json_extract(value, '$.user_id'), json_extract(value, '$.seq'),
+               json_extract(value, '$.frame'), ?
+        FROM json_each(?)
+        "#,
+        created_at,
+        rows
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM user_events
+        WHERE user_id IN (SELECT value FROM json_each(?))
+          AND seq <= (SELECT event_seq FROM users WHERE users.id = user_events.user_id) - ?
+        "#,
+        recipient_ids,
+        MAX_LOGGED_EVENTS_PER_USER
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    let mut delivered_to = Vec::new();
+    for (user_id, frame) in frames {
+        if deliver(state, user_id, &frame) {
+            delivered_to.push(user_id);
+        }
+    }
+    drop(ordering);
+    if let WsEvent::Message(message) | WsEvent::ThreadMessage(message) = event {
+        if !delivered_to.is_empty() {
+            record_deliveries(state, &delivered_to, &[message.id]).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn broadcast_to_chat(
+    state: &AppState,
+    chat_id: ChatId,
+    event: &WsEvent,
+) -> Result<(), AppError> {
+    let recipients = sqlx::query_scalar!(
+        "SELECT user_id FROM chat_participants WHERE chat_id = ?",
+        chat_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    publish(state, &recipients, event).await
+}
+
+async fn send_to_user(state: &AppState, user_id: UserId, event: &WsEvent) -> Result<(), AppError> {
+    publish(state, &[user_id], event).await
+}
+
+/// Moves the caller's read marker forward to `message_id` and notifies the chat. Markers never
+/// move backwards.
+async fn mark_read(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    chat_id: ChatId,
+    message_id: MessageId,
+) -> Result<(), AppError> {
+    if !is_participant(state, chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to view this chat".to_string(),
+        ));
+    }
+    let exists = sqlx::query_scalar!(
+        "SELECT 1 FROM messages WHERE id = ? AND chat_id = ?",
+        message_id,
+        chat_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .is_some();
+    if !exists {
+        return Err(AppError::NotFound(format!(
+            "Message with ID {} not found",
+            message_id
+        )));
+    }
+    let affected = sqlx::query!(
+        r#"
+        UPDATE chat_participants SET last_read_message_id = ?
+        WHERE chat_id = ? AND user_id = ?
+          AND (last_read_message_id IS NULL OR last_read_message_id < ?)
+        "#,
+        message_id,
+        chat_id,
+        auth.user_id,
+        message_id
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected();
+    if affected > 0 {
+        let event = WsEvent::ReadReceipt {
+            chat_id,
+            user_id: auth.user_id,
+            last_read_message_id: message_id,
+        };
+        broadcast_to_chat(state, chat_id, &event).await?;
+    }
+    Ok(())
+}
+
+pub async fn mark_read_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(chat_id): Path<ChatId>,
+    Json(payload): Json<MarkRead>,
+) -> Result<StatusCode, AppError> {
+    mark_read(&state, &auth, chat_id, payload.message_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_chat_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(chat_id): Path<ChatId>,
+) -> Result<Json<Chat>, AppError> {
+    if !is_participant(&state, chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to view this chat info".to_string(),
+        ));
+    }
+
+    Ok(Json(fetch_chat(&state, chat_id).await?))
+}
+
+async fn fetch_chat(state: &AppState, chat_id: ChatId) -> Result<Chat, AppError> {
+    let row = sqlx::query!(
+        r#"
+        SELECT id as "id!", name, chat_type as "chat_type: ChatType", created_at as "created_at!", server_id,
+               (SELECT COUNT(*) FROM pinned_messages WHERE chat_id = chats.id) as "pin_count!: i64"
+        FROM chats
+        WHERE id = ?
+        "#,
+        chat_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Chat with ID {} not found", chat_id)))?;
+
+    let participants = sqlx::query_scalar!(
+        "SELECT user_id FROM chat_participants WHERE chat_id = ?",
+        chat_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Chat {
+        id: row.id,
+        name: row.name,
+        chat_type: row.chat_type,
+        created_at: row.created_at,
+        server_id: row.server_id,
+        participants,
+        pin_count: row.pin_count,
+    })
+}
+
+const DEFAULT_HISTORY_LIMIT: i64 = 50;
+const MAX_HISTORY_LIMIT: i64 = 100;
+
+/// Which messages of a chat a history page is drawn from.
+#[derive(Clone, Copy)]
+enum HistoryScope {
+    /// The main stream of the chat, with thread replies only when asked for.
+    Chat { include_threads: bool },
+    /// The replies in the thread of the given root message.
+    Thread(MessageId),
+}
+
+/// Which side of the cursor a history page is on.
+#[derive(Clone, Copy)]
+enum PageSide {
+    /// Older than the cursor, newest first.
+    Before,
+    /// Newer than the cursor, oldest first.
+    After,
+    /// The cursor and newer messages, oldest first.
+    From,
+}
+
+/// Loads up to `limit` messages in `scope` visible to `user_id` on `side` of `cursor`.
+async fn fetch_history_page(
+    state: &AppState,
+    chat_id: ChatId,
+    user_id: UserId,
+    scope: HistoryScope,
+    cursor: MessageId,
+    side: PageSide,
+    limit: i64,
+) -> Result<Vec<Message>, AppError> {
+    // Thread replies are kept when included in the chat, or when paging a thread
+    let (include_threads, thread_root_id) = match scope {
+        HistoryScope::Chat { include_threads } => (include_threads, None),
+        HistoryScope::Thread(root_id) => (true, Some(root_id)),
+    };
+    let rows = match side {
+        PageSide::Before => {
+            sqlx::query_as!(
+                MessageRow,
+                r#"
+                SELECT m.id as "id!", m.chat_id as "chat_id!", m.sender_id as "sender_id!", m.content,
+               m.timestamp as "timestamp!", m.edited_at, m.deleted_at, m.reply_to, m.thread_root_id,
+               COALESCE(t.reply_count, 0) as "thread_reply_count!: i64",
+               t.last_reply_at as "thread_last_reply_at?: String",
+               m.forwarded as "forwarded!: bool", m.forwarded_from_sender_id, m.forwarded_from_chat_id
+                FROM messages m
+                LEFT JOIN (
+                    SELECT thread_root_id, COUNT(*) AS reply_count, MAX(timestamp) AS last_reply_at
+                    FROM messages
+                    WHERE chat_id = ?1 AND thread_root_id IS NOT NULL AND deleted_at IS NULL
+                    GROUP BY thread_root_id
+                ) t ON t.thread_root_id = m.id
+                WHERE m.chat_id = ?1 AND m.id < ?2
+                  AND (?3 OR m.thread_root_id IS NULL)
+                  AND (?4 IS NULL OR m.thread_root_id = ?4)
+                  AND NOT EXISTS (
+                    SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = ?5
+                  )
+                ORDER BY m.id DESC
+                LIMIT ?6
+                "#,
+                chat_id,
+                cursor,
+                include_threads,
+                thread_root_id,
+                user_id,
+                limit
+            )
+            .fetch_all(&state.pool)
+            .await?
+        }
+        PageSide::After | PageSide::From => {
+            let include_cursor = matches!(side, PageSide::From);
+            sqlx::query_as!(
+                MessageRow,
+                r#"
+                SELECT m.id as "id!", m.chat_id as "chat_id!", m.sender_id as "sender_id!", m.content,
+               m.timestamp as "timestamp!", m.edited_at, m.deleted_at, m.reply_to, m.thread_root_id,
+               COALESCE(t.reply_count, 0) as "thread_reply_count!: i64",
+               t.last_reply_at as "thread_last_reply_at?: String",
+               m.forwarded as "forwarded!: bool", m.forwarded_from_sender_id, m.forwarded_from_chat_id
+                FROM messages m
+                LEFT JOIN (
+                    SELECT thread_root_id, COUNT(*) AS reply_count, MAX(timestamp) AS last_reply_at
+                    FROM messages
+                    WHERE chat_id = ?1 AND thread_root_id IS NOT NULL AND deleted_at IS NULL
+                    GROUP BY thread_root_id
+                ) t ON t.thread_root_id = m.id
+                WHERE m.chat_id = ?1 AND (m.id > ?2 OR (?3 AND m.id = ?2))
+                  AND (?4 OR m.thread_root_id IS NULL)
+                  AND (?5 IS NULL OR m.thread_root_id = ?5)
+                  AND NOT EXISTS (
+                    SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = ?6
+                  )
+                ORDER BY m.id ASC
+                LIMIT ?7
+                "#,
+                chat_id,
+                cursor,
+                include_cursor,
+                include_threads,
+                thread_root_id,
+                user_id,
+                limit
+            )
+            .fetch_all(&state.pool)
+            .await?
+        }
+    };
+    let mut messages: Vec<Message> = rows.into_iter().map(Message::from).collect();
+    fill_message_files(state, &mut messages).await?;
+    fill_reply_previews(state, &mut messages).await?;
+    Ok(messages)
+}
+
+/// Loads the page of messages in `scope` selected by the cursors of `query`.
+async fn fetch_history(
+    state: &AppState,
+    chat_id: ChatId,
+    user_id: UserId,
+    scope: HistoryScope,
+    query: &HistoryQuery,
+) -> Result<ChatHistoryResponse, AppError> {
+    let cursors = [query.before, query.after, query.around];
+    if cursors.iter().filter(|c| c.is_some()).count() > 1 {
+        return Err(AppError::BadRequest(
+            "Only one of before, after and around may be given".to_string(),
+        ));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+    if !(1..=MAX_HISTORY_LIMIT).contains(&limit) {
+        return Err(AppError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_HISTORY_LIMIT
+        )));
+    }
+
+    // Each side is fetched with one extra row to tell whether more messages exist beyond it.
+    let (mut older, newer) = match (query.before, query.after, query.around) {
+        (_, Some(after), _) => (
+            Vec::new(),
+            fetch_history_page(
+                state,
+                chat_id,
+                user_id,
+                scope,
+                after,
+                PageSide::After,
+                limit + 1,
+            )
+            .await?,
+        ),
+        (_, _, Some(around)) => {
+            fetch_message(state, chat_id, around).await?;
+            let older = fetch_history_page(
+                state,
+                chat_id,
+                user_id,
+                scope,
+                around,
+                PageSide::Before,
+                limit / 2 + 1,
+            )
+            .await?;
+            let newer = fetch_history_page(
+                state,
+                chat_id,
+                user_id,
+                scope,
+                around,
+                PageSide::From,
+                limit - limit / 2 + 1,
+            )
+            .await?;
+            (older, newer)
+        }
+        (before, _, _) => (
+            fetch_history_page(
+                state,
+                chat_id,
+                user_id,
+                scope,
+                before.unwrap_or(MessageId::MAX),
+                PageSide::Before,
+                limit + 1,
+            )
+            .await?,
+            Vec::new(),
+        ),
+    };
+
+    let (older_limit, newer_limit) = if query.around.is_some() {
+        (limit / 2, limit - limit / 2)
+    } else {
+        (limit, limit)
+    };
+    let (older_limit, newer_limit) = (older_limit as usize, newer_limit as usize);
+    // Paging forward from `after` implies there is history before the page, and vice versa.
+    let has_older = older.len() > older_limit || query.after.is_some();
+    let has_newer = newer.len() > newer_limit || query.before.is_some();
+    older.truncate(older_limit);
+    older.reverse();
+
+    let mut messages = older;
+    messages.extend(newer.into_iter().take(newer_limit));
+    fill_for_viewer(state, user_id, &mut messages).await?;
+    let next_cursor = messages.first().filter(|_| has_older).map(|m| m.id);
+    let prev_cursor = messages.last().filter(|_| has_newer).map(|m| m.id);
+    Ok(ChatHistoryResponse {
+        chat_id,
+        messages,
+        next_cursor,
+        prev_cursor,
+    })
+}
+
+pub async fn get_history_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(chat_id): Path<ChatId>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<ChatHistoryResponse>, AppError> {
+    if !is_participant(&state, chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to view this chat".to_string(),
+        ));
+    }
+    let scope = HistoryScope::Chat {
+        include_threads: query.include_threads,
+    };
+    Ok(Json(
+        fetch_history(&state, chat_id, auth.user_id, scope, &query).await?,
+    ))
+}
+
+/// Returns the root message of a thread with a page of its replies.
+pub async fn get_thread_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((chat_id, message_id)): Path<(ChatId, MessageId)>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<ThreadHistoryResponse>, AppError> {
+    if !is_participant(&state, chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to view this chat".to_string(),
+        ));
+    }
+    let mut root = fetch_message(&state, chat_id, message_id).await?;
+    if root.thread_root_id.is_some() {
+        return Err(AppError::BadRequest(
+            "Thread replies do not have threads".to_string(),
+        ));
+    }
+    fill_for_viewer(&state, auth.user_id, std::slice::from_mut(&mut root)).await?;
+    let history = fetch_history(
+        &state,
+        chat_id,
+        auth.user_id,
+        HistoryScope::Thread(message_id),
+        &query,
+    )
+    .await?;
+    Ok(Json(ThreadHistoryResponse { root, history }))
+}
+
+pub async fn ws_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Query(query): Query<WsConnectQuery>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, state, auth, query))
+}
+
+/// Builds the frames a connection missed: the logged events after `since`, or
+/// `resync_required` if some were already pruned. Also returns the sequence number they cover,
+/// after which live delivery takes over.
+async fn replay_frames(
+    state: &AppState,
+    user_id: UserId,
+    since: Option<EventSeq>,
+) -> Result<(Vec<String>, EventSeq), AppError> {
+    let current = sqlx::query_scalar!("SELECT event_seq FROM users WHERE id = ?", user_id)
+        .fetch_one(&state.pool)
+        .await?;
+    let mut frames = Vec::new();
+    if let Some(since) = since {
+        let oldest = sqlx::query_scalar!(
+            r#"SELECT MIN(seq) as "seq?: EventSeq" FROM user_events WHERE user_id = ?"#,
+            user_id
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        let complete =
+            since == current || (since < current && oldest.is_some_and(|seq| seq <= since + 1));
+        if complete {
+            frames = sqlx::query_scalar!(
+                "SELECT frame FROM user_events WHERE user_id = ? AND seq > ? AND seq <= ? ORDER BY seq",
+                user_id,
+                since,
+                current
+            )
+            .fetch_all(&state.pool)
+            .await?;
+        } else {
+            frames.push(serde_json::to_string(&WsEvent::ResyncRequired { seq: current }).unwrap());
+        }
+    }
+    Ok((frames, current))
+}
+
+/// Parses and executes one client frame, returning the `ack` or `error` reply for it.
+async fn handle_client_frame(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    connection_id: &ConnectionId,
+    text: &str,
+) -> WsEvent {
+    let value = match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(value) => value,
+        Err(e) => {
+            return WsEvent::Error {
+                request_id: None,
+                code: "invalid_frame".to_string(),
+                message: e.to_string(),
+            }
+        }
+    };
+    // Read separately so that malformed frames can still be answered with their request id
+    let request_id = value
+        .get("request_id")
+        .and_then(|id| id.as_str())
+        .map(str::to_string);
+    let envelope = match serde_json::from_value::<WsClientEnvelope>(value) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            return WsEvent::Error {
+                request_id,
+                code: "invalid_frame".to_string(),
+                message: e.to_string(),
+            }
+        }
+    };
+    let request_id = envelope.request_id;
+    let result = match envelope.frame {
+        WsClientFrame::SendMessage(payload) => {
+            process_message(state, auth, payload).await.map(Some)
+        }
+        WsClientFrame::EditMessage(payload) => edit_message(
+            state,
+            auth,
+            payload.chat_id,
+            payload.message_id,
+            payload.content,
+        )
+        .await
+        .map(Some),
+        WsClientFrame::ForwardMessage(payload) => forward_message(
+            state,
+            auth,
+            payload.chat_id,
+            payload.message_id,
+            payload.target_chat_id,
+        )
+        .await
+        .map(Some),
+        WsClientFrame::TypingStart(payload) => start_typing(state, auth, payload.chat_id)
+            .await
+            .map(|_| None),
+        WsClientFrame::TypingStop(payload) => end_typing(state, payload.chat_id, auth.user_id)
+            .await
+            .map(|_| None),
+        WsClientFrame::SetPresence(payload) => {
+            set_presence(state, auth, connection_id, payload.status)
+                .await
+                .map(|_| None)
+        }
+        WsClientFrame::MarkRead(payload) => {
+            mark_read(state, auth, payload.chat_id, payload.message_id)
+                .await
+                .map(|_| None)
+        }
+        WsClientFrame::AddReaction(payload) => add_reaction(
+            state,
+            auth,
+            payload.chat_id,
+            payload.message_id,
+            payload.reaction,
+        )
+        .await
+        .map(|_| None),
+        WsClientFrame::RemoveReaction(payload) => remove_reaction(
+            state,
+            auth,
+            payload.chat_id,
+            payload.message_id,
+            payload.reaction,
+        )
+        .await
+        .map(|_| None),
+    };
+    let result = match result {
+        Ok(Some(mut message)) => {
+            fill_for_viewer(state, auth.user_id, std::slice::from_mut(&mut message))
+                .await
+                .map(|_| Some(message))
+        }
+        other => other,
+    };
+    match result {
+        Ok(message) => WsEvent::Ack {
+            request_id,
+            message_id: message.as_ref().map(|m| m.id),
+            message,
+        },
+        Err(e) => {
+            tracing::error!("Failed to process WS message: {:?}", e);
+            WsEvent::Error {
+                request_id,
+                code: e.code().to_string(),
+                message: e.into_message(),
+            }
+        }
+    }
+}
+
+/// How long a typing indicator lasts unless the client renews it with another `typing_start`.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// Pushes an ephemeral event to the chat's participants other than `user_id`, bypassing the
+/// event log.
+async fn broadcast_ephemeral(
+    state: &AppState,
+    chat_id: ChatId,
+    user_id: UserId,
+    event: &WsEvent,
+) -> Result<(), AppError> {
+    let recipients = sqlx::query_scalar!(
+        "SELECT user_id FROM chat_participants WHERE chat_id = ? AND user_id != ?",
+        chat_id,
+        user_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let event = OutboundEvent {
+        seq: None,
+        json: serde_json::to_string(event).unwrap(),
+    };
+    for recipient in recipients {
+        deliver(state, recipient, &event);
+    }
+    Ok(())
+}
+
+async fn start_typing(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    chat_id: ChatId,
+) -> Result<(), AppError> {
+    if !is_participant(state, chat_id, auth.user_id).await? {
+        return Err(AppError::AuthError(
+            "Not authorized to send to this chat".to_string(),
+        ));
+    }
+    let key = (chat_id, auth.user_id);
+    let expires_at = Instant::now() + TYPING_TIMEOUT;
+    let started = match state.typing.entry(key) {
+        Entry::Occupied(mut entry) => {
+            entry.get_mut().expires_at = expires_at;
+            false
+        }
+        Entry::Vacant(entry) => {
+            let expiry = tokio::spawn(expire_typing(state.clone(), key)).abort_handle();
+            entry.insert(TypingIndicator { expires_at, expiry });
+            true
+        }
+    };
+    if started {
+        let event = WsEvent::TypingStarted {
+            chat_id,
+            user_id: auth.user_id,
+        };
+        broadcast_ephemeral(state, chat_id, auth.user_id, &event).await?;
+    }
+    Ok(())
+}
+
+/// Sleeps until the indicator's current deadline, following renewals, then ends it.
+async fn expire_typing(state: AppState, key: (ChatId, UserId)) {
+    loop {
+        let Some(expires_at) = state.typing.get(&key).map(|typing| typing.expires_at) else {
+            return;
+        };
+        if expires_at > Instant::now() {
+            tokio::time::sleep_until(expires_at).await;
+            continue;
+        }
+        let expired = state
+            .typing
+            .remove_if(&key, |_, typing| typing.expires_at <= Instant::now())
+            .is_some();
+        if expired {
+            let (chat_id, user_id) = key;
+            let event = WsEvent::TypingStopped { chat_id, user_id };
+            if let Err(e) = broadcast_ephemeral(&state, chat_id, user_id, &event).await {
+                tracing::error!("Failed to expire typing indicator: {:?}", e);
+            }
+        }
+        return;
+    }
+}
+
+/// Ends the user's typing indicator in the chat, if shown, and tells the other participants.
+async fn end_typing(state: &AppState, chat_id: ChatId, user_id: UserId) -> Result<(), AppError> {
+    if let Some((_, typing)) = state.typing.remove(&(chat_id, user_id)) {
+        typing.expiry.abort();
+        let event = WsEvent::TypingStopped { chat_id, user_id };
+        broadcast_ephemeral(state, chat_id, user_id, &event).await?;
+    }
+    Ok(())
+}
+
+fn presence_of(state: &AppState, user_id: UserId) -> PresenceStatus {
+    let Some(connections) = state.active_connections.get(&user_id) else {
+        return PresenceStatus::Offline;
+    };
+    let statuses: Vec<PresenceStatus> = connections.values().map(|c| c.info.status).collect();
+    if statuses.contains(&PresenceStatus::Online) {
+        PresenceStatus::Online
+    } else if statuses.is_empty() {
+        PresenceStatus::Offline
+    } else {
+        PresenceStatus::Away
+    }
+}
+
+/// Notifies everyone sharing a chat with the user if their presence differs from `before`,
+/// recording `last_seen_at` when they go offline.
+async fn update_presence(
+    state: &AppState,
+    user_id: UserId,
+    before: PresenceStatus,
+) -> Result<(), AppError> {
+    let status = presence_of(state, user_id);
+    if status == before {
+        return Ok(());
+    }
+    let last_seen_at = if status == PresenceStatus::Offline {
+        let now = chrono::Utc::now().to_rfc3339();
+        sqlx::query!(
+            "UPDATE users SET last_seen_at = ? WHERE id = ?",
+            now,
+            user_id
+        )
+        .execute(&state.pool)
+        .await?;
+        Some(now)
+    } else {
+        sqlx::query_scalar!("SELECT last_seen_at FROM users WHERE id = ?", user_id)
+            .fetch_one(&state.pool)
+            .await?
+    };
+    let recipients = sqlx::query_scalar!(
+        r#"
+        SELECT DISTINCT other.user_id
+        FROM chat_participants own
+        JOIN chat_participants other ON other.chat_id = own.chat_id
+        WHERE own.user_id = ? AND other.user_id != ?
+        "#,
+        user_id,
+        user_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let event = OutboundEvent {
+        seq: None,
+        json: serde_json::to_string(&WsEvent::PresenceChanged {
+            user_id,
+            status,
+            last_seen_at,
+        })
+        .unwrap(),
+    };
+    for recipient in recipients {
+        deliver(state, recipient, &event);
+    }
+    Ok(())
+}
+
+async fn set_presence(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    connection_id: &ConnectionId,
+    status: PresenceStatus,
+) -> Result<(), AppError> {
+    if status == PresenceStatus::Offline {
+        return Err(AppError::BadRequest(
+            "Presence can only be set to online or away".to_string(),
+        ));
+    }
+    let before = presence_of(state, auth.user_id);
+    if let Some(mut connections) = state.active_connections.get_mut(&auth.user_id) {
+        if let Some(connection) = connections.get_mut(connection_id) {
+            connection.info.status = status;
+        }
+    }
+    update_presence(state, auth.user_id, before).await
+}
+
+async fn send_frames(
+    sender: &mut SplitSink<WebSocket, WsMessage>,
+    frames: Vec<String>,
+) -> Result<(), axum::Error> {
+    for frame in frames {
+        sender.send(WsMessage::Text(frame)).await?;
+    }
+    Ok(())
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    auth: AuthenticatedUser,
+    query: WsConnectQuery,
+) {
+    let (mut sender, mut receiver) = socket.split();
+    let user_id = auth.user_id;
+    let connection_id = uuid::Uuid::new_v4().simple().to_string();
+    let (tx, mut rx) = mpsc::channel::<OutboundEvent>(state.connection_queue_capacity);
+    let close = Arc::new(Notify::new());
+    let lagged = Arc::new(Notify::new());
+    let presence_before = presence_of(&state, user_id);
+    // Registered before reading the event log so that nothing falls between replay and live
+    state.active_connections.entry(user_id).or_default().insert(
+        connection_id.clone(),
+        ConnectionHandle {
+            info: Connection {
+                id: connection_id.clone(),
+                session_id: auth.session_id,
+                device_id: query.device_id,
+                connected_at: chrono::Utc::now().to_rfc3339(),
+                status: PresenceStatus::Online,
+            },
+            sender: tx.clone(),
+            close: close.clone(),
+            lagged: lagged.clone(),
+        },
+    );
+    if let Err(e) = update_presence(&state, user_id, presence_before).await {
+        tracing::error!("Failed to update presence: {:?}", e);
+    }
+    let replay_state = state.clone();
+    let mut send_task = tokio::spawn(async move {
+        let (mut frames, mut last_seq) =
+            match replay_frames(&replay_state, user_id, query.since).await {
+                Ok(replay) => replay,
+                Err(e) => {
+                    tracing::error!("Failed to replay WS events: {:?}", e);
+                    return;
+                }
+            };
+        let replayed = replayed_message_ids(&frames);
+        frames.push(serde_json::to_string(&WsEvent::Ready { seq: last_seq }).unwrap());
+        if send_frames(&mut sender, frames).await.is_err() {
+            return;
+        }
+        record_replayed_deliveries(&replay_state, user_id, &replayed).await;
+        loop {
+            let event = tokio::select! {
+                biased;
+                _ = close.notified() => {
+                    let _ = sender.send(WsMessage::Close(None)).await;
+                    break;
+                }
+                _ = lagged.notified() => {
+                    // Events were dropped from the full queue; resend them from the event log
+                    let frames = match replay_frames(&replay_state, user_id, Some(last_seq)).await {
+                        Ok((frames, seq)) => {
+                            last_seq = seq;
+                            frames
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to replay WS events: {:?}", e);
+                            break;
+                        }
+                    };
+                    tracing::warn!("WS connection of user {} lagged, resending {} frames", user_id, frames.len());
+                    let replayed = replayed_message_ids(&frames);
+                    if send_frames(&mut sender, frames).await.is_err() {
+                        break;
+                    }
+                    record_replayed_deliveries(&replay_state, user_id, &replayed).await;
+                    continue;
+                }
+                Some(event) = rx.recv() => event,
+            };
+            if let Some(seq) = event.seq {
+                // Already sent during replay
+                if seq <= last_seq {
+                    continue;
+                }
+                last_seq = seq;
+            }
+            if let Err(_e) = sender.send(WsMessage::Text(event.json)).await {
+                // Client disconnected
+                break;
+            }
+        }
+    });
+    let recv_state = state.clone();
+    let recv_connection_id = connection_id.clone();
+    let mut recv_task = tokio::spawn(async move {
+        while let Some(Ok(msg)) = receiver.next().await {
+            match msg {
+                WsMessage::Text(text) => {
+                    let reply =
+                        handle_client_frame(&recv_state, &auth, &recv_connection_id, &text).await;
+                    // Queued behind any events published while handling the frame
+                    let reply = OutboundEvent {
+                        seq: None,
+                        json: serde_json::to_string(&reply).unwrap(),
+                    };
+                    if tx.send(reply).await.is_err() {
+                        break;
+                    }
                 }
                 WsMessage::Close(_) => break,
                 _ => {}
@@ -561,4 +4533,750 @@ async fn handle_socket(socket: WebSocket, state: AppState, auth: AuthenticatedUs
         _ = (&mut send_task) => recv_task.abort(),
         _ = (&mut recv_task) => send_task.abort(),
     };
+    let presence_before = presence_of(&state, user_id);
+    if let Some(mut connections) = state.active_connections.get_mut(&user_id) {
+        connections.remove(&connection_id);
+    }
+    state
+        .active_connections
+        .remove_if(&user_id, |_, connections| connections.is_empty());
+    if let Err(e) = update_presence(&state, user_id, presence_before).await {
+        tracing::error!("Failed to update presence: {:?}", e);
+    }
+}
+
+pub async fn list_connections_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+) -> Result<Json<Vec<Connection>>, AppError> {
+    let mut connections: Vec<Connection> = state
+        .active_connections
+        .get(&auth.user_id)
+        .map(|connections| connections.values().map(|c| c.info.clone()).collect())
+        .unwrap_or_default();
+    connections.sort_by(|a, b| a.connected_at.cmp(&b.connected_at));
+    Ok(Json(connections))
+}
+
+pub async fn close_connection_handler(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(connection_id): Path<ConnectionId>,
+) -> Result<StatusCode, AppError> {
+    if close_connections(&state, auth.user_id, |c| c.id == connection_id) == 0 {
+        return Err(AppError::NotFound(format!(
+            "Connection with ID {} not found",
+            connection_id
+        )));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::FileAssetIn;
+    use dashmap::DashMap;
+    use sqlx::SqlitePool;
+
+    fn test_state(pool: SqlitePool) -> AppState {
+        AppState {
+            pool,
+            active_connections: Arc::new(DashMap::new()),
+            jwt_secret: "test".to_string(),
+            delete_for_everyone_window_secs: 3600,
+            connection_queue_capacity: 16,
+            typing: Arc::new(DashMap::new()),
+            publish_lock: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    async fn insert_user(pool: &SqlitePool, username: &str) -> UserId {
+        sqlx::query_scalar("INSERT INTO users (username) VALUES (?) RETURNING id")
+            .bind(username)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn insert_chat(pool: &SqlitePool, members: &[UserId]) -> ChatId {
+        let chat_id =
+            sqlx::query_scalar("INSERT INTO chats (chat_type) VALUES ('group') RETURNING id")
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        for user_id in members {
+            sqlx::query("INSERT INTO chat_participants (chat_id, user_id) VALUES (?, ?)")
+                .bind(chat_id)
+                .bind(user_id)
+                .execute(pool)
+                .await
+                .unwrap();
+        }
+        chat_id
+    }
+
+    async fn insert_message(pool: &SqlitePool, chat_id: ChatId, sender_id: UserId) -> MessageId {
+        sqlx::query_scalar(
+            "INSERT INTO messages (chat_id, sender_id, content, timestamp) VALUES (?, ?, 'hi', ?) RETURNING id",
+        )
+        .bind(chat_id)
+        .bind(sender_id)
+        .bind(chrono::Utc::now().to_rfc3339())
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    fn auth_as(user_id: UserId) -> AuthenticatedUser {
+        AuthenticatedUser {
+            user_id,
+            session_id: 1,
+        }
+    }
+
+    fn history_query(
+        before: Option<MessageId>,
+        after: Option<MessageId>,
+        around: Option<MessageId>,
+        limit: i64,
+    ) -> HistoryQuery {
+        HistoryQuery {
+            before,
+            after,
+            around,
+            limit: Some(limit),
+            include_threads: false,
+        }
+    }
+
+    fn ids(page: &ChatHistoryResponse) -> Vec<MessageId> {
+        page.messages.iter().map(|m| m.id).collect()
+    }
+
+    const CHAT: HistoryScope = HistoryScope::Chat {
+        include_threads: false,
+    };
+
+    #[sqlx::test]
+    async fn history_pages_backwards_and_forwards(pool: SqlitePool) {
+        let user = insert_user(&pool, "alice").await;
+        let chat = insert_chat(&pool, &[user]).await;
+        let mut messages = Vec::new();
+        for _ in 0..5 {
+            messages.push(insert_message(&pool, chat, user).await);
+        }
+        let state = test_state(pool);
+
+        let latest = fetch_history(
+            &state,
+            chat,
+            user,
+            CHAT,
+            &history_query(None, None, None, 2),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ids(&latest), messages[3..5]);
+        assert_eq!(latest.next_cursor, Some(messages[3]));
+        assert_eq!(latest.prev_cursor, None);
+
+        let older = history_query(latest.next_cursor, None, None, 2);
+        let older = fetch_history(&state, chat, user, CHAT, &older)
+            .await
+            .unwrap();
+        assert_eq!(ids(&older), messages[1..3]);
+        assert_eq!(older.next_cursor, Some(messages[1]));
+        assert_eq!(older.prev_cursor, Some(messages[2]));
+
+        let oldest = history_query(older.next_cursor, None, None, 2);
+        let oldest = fetch_history(&state, chat, user, CHAT, &oldest)
+            .await
+            .unwrap();
+        assert_eq!(ids(&oldest), messages[0..1]);
+        assert_eq!(oldest.next_cursor, None);
+
+        let newer = history_query(None, Some(messages[0]), None, 3);
+        let newer = fetch_history(&state, chat, user, CHAT, &newer)
+            .await
+            .unwrap();
+        assert_eq!(ids(&newer), messages[1..4]);
+        assert_eq!(newer.next_cursor, Some(messages[1]));
+        assert_eq!(newer.prev_cursor, Some(messages[3]));
+    }
+
+    #[sqlx::test]
+    async fn history_around_includes_the_target(pool: SqlitePool) {
+        let user = insert_user(&pool, "alice").await;
+        let chat = insert_chat(&pool, &[user]).await;
+        let mut messages = Vec::new();
+        for _ in 0..5 {
+            messages.push(insert_message(&pool, chat, user).await);
+        }
+        let state = test_state(pool);
+
+        let query = history_query(None, None, Some(messages[2]), 3);
+        let page = fetch_history(&state, chat, user, CHAT, &query)
+            .await
+            .unwrap();
+        assert_eq!(ids(&page), messages[1..4]);
+        assert_eq!(page.next_cursor, Some(messages[1]));
+        assert_eq!(page.prev_cursor, Some(messages[3]));
+    }
+
+    #[sqlx::test]
+    async fn history_rejects_bad_cursors_and_limits(pool: SqlitePool) {
+        let user = insert_user(&pool, "alice").await;
+        let chat = insert_chat(&pool, &[user]).await;
+        let message = insert_message(&pool, chat, user).await;
+        let state = test_state(pool);
+
+        let past_the_end = history_query(None, Some(MessageId::MAX), None, 10);
+        let page = fetch_history(&state, chat, user, CHAT, &past_the_end)
+            .await
+            .unwrap();
+        assert!(page.messages.is_empty());
+
+        let two_cursors = history_query(Some(message), Some(message), None, 10);
+        assert!(matches!(
+            fetch_history(&state, chat, user, CHAT, &two_cursors).await,
+            Err(AppError::BadRequest(_))
+        ));
+        for limit in [0, MAX_HISTORY_LIMIT + 1] {
+            let query = history_query(None, None, None, limit);
+            assert!(matches!(
+                fetch_history(&state, chat, user, CHAT, &query).await,
+                Err(AppError::BadRequest(_))
+            ));
+        }
+    }
+
+    fn renamed(chat_id: ChatId, name: &str, renamed_by: UserId) -> WsEvent {
+        WsEvent::ChatRenamed {
+            chat_id,
+            name: name.to_string(),
+            renamed_by,
+        }
+    }
+
+    fn frame_seq(frame: &str) -> EventSeq {
+        let value: serde_json::Value = serde_json::from_str(frame).unwrap();
+        value["seq"].as_i64().unwrap()
+    }
+
+    #[sqlx::test]
+    async fn publish_numbers_each_recipient_log(pool: SqlitePool) {
+        let alice = insert_user(&pool, "alice").await;
+        let bob = insert_user(&pool, "bob").await;
+        let chat = insert_chat(&pool, &[alice, bob]).await;
+        let state = test_state(pool);
+
+        publish(&state, &[alice, bob], &renamed(chat, "one", alice))
+            .await
+            .unwrap();
+        publish(&state, &[alice], &renamed(chat, "two", alice))
+            .await
+            .unwrap();
+
+        let (frames, current) = replay_frames(&state, alice, Some(0)).await.unwrap();
+        assert_eq!(current, 2);
+        assert_eq!(
+            frames.iter().map(|f| frame_seq(f)).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        let (frames, current) = replay_frames(&state, bob, Some(0)).await.unwrap();
+        assert_eq!(current, 1);
+        assert_eq!(frames.len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn replay_returns_only_the_missed_events(pool: SqlitePool) {
+        let alice = insert_user(&pool, "alice").await;
+        let chat = insert_chat(&pool, &[alice]).await;
+        let state = test_state(pool);
+        for name in ["one", "two", "three"] {
+            publish(&state, &[alice], &renamed(chat, name, alice))
+                .await
+                .unwrap();
+        }
+
+        let (frames, _) = replay_frames(&state, alice, Some(1)).await.unwrap();
+        assert_eq!(
+            frames.iter().map(|f| frame_seq(f)).collect::<Vec<_>>(),
+            [2, 3]
+        );
+        let (frames, current) = replay_frames(&state, alice, Some(3)).await.unwrap();
+        assert!(frames.is_empty());
+        assert_eq!(current, 3);
+        let (frames, _) = replay_frames(&state, alice, None).await.unwrap();
+        assert!(frames.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn replay_requires_resync_across_a_gap(pool: SqlitePool) {
+        let alice = insert_user(&pool, "alice").await;
+        let chat = insert_chat(&pool, &[alice]).await;
+        let state = test_state(pool);
+        for name in ["one", "two", "three"] {
+            publish(&state, &[alice], &renamed(chat, name, alice))
+                .await
+                .unwrap();
+        }
+        // As if the oldest events had been pruned
+        sqlx::query("DELETE FROM user_events WHERE seq <= 2")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+
+        let (frames, _) = replay_frames(&state, alice, Some(2)).await.unwrap();
+        assert_eq!(frames.iter().map(|f| frame_seq(f)).collect::<Vec<_>>(), [3]);
+        for since in [0, 1, 4] {
+            let (frames, _) = replay_frames(&state, alice, Some(since)).await.unwrap();
+            assert_eq!(
+                frames,
+                [serde_json::to_string(&WsEvent::ResyncRequired { seq: 3 }).unwrap()]
+            );
+        }
+    }
+
+    /// Registers a live connection of the user whose queue holds `capacity` events.
+    fn connect(
+        state: &AppState,
+        user_id: UserId,
+        capacity: usize,
+    ) -> mpsc::Receiver<OutboundEvent> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let handle = ConnectionHandle {
+            info: Connection {
+                id: uuid::Uuid::new_v4().simple().to_string(),
+                session_id: 1,
+                device_id: None,
+                connected_at: chrono::Utc::now().to_rfc3339(),
+                status: PresenceStatus::Online,
+            },
+            sender,
+            close: Arc::new(Notify::new()),
+            lagged: Arc::new(Notify::new()),
+        };
+        state
+            .active_connections
+            .entry(user_id)
+            .or_default()
+            .insert(handle.info.id.clone(), handle);
+        receiver
+    }
+
+    #[sqlx::test]
+    async fn concurrent_publishes_are_queued_in_seq_order(pool: SqlitePool) {
+        let alice = insert_user(&pool, "alice").await;
+        let chat = insert_chat(&pool, &[alice]).await;
+        let state = test_state(pool);
+        let mut receiver = connect(&state, alice, 64);
+
+        let tasks: Vec<_> = (0..20)
+            .map(|i| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let event = renamed(chat, &i.to_string(), alice);
+                    publish(&state, &[alice], &event).await.unwrap();
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let mut seqs = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            seqs.push(event.seq.unwrap());
+        }
+        assert_eq!(seqs, (1..=20).collect::<Vec<_>>());
+    }
+
+    #[sqlx::test]
+    async fn thread_summaries_leave_out_deleted_replies(pool: SqlitePool) {
+        let alice = insert_user(&pool, "alice").await;
+        let chat = insert_chat(&pool, &[alice]).await;
+        let root = insert_message(&pool, chat, alice).await;
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            let reply = insert_message(&pool, chat, alice).await;
+            sqlx::query("UPDATE messages SET thread_root_id = ? WHERE id = ?")
+                .bind(root)
+                .bind(reply)
+                .execute(&pool)
+                .await
+                .unwrap();
+            replies.push(reply);
+        }
+        let state = test_state(pool);
+
+        delete_message_handler(
+            State(state.clone()),
+            auth_as(alice),
+            Path((chat, replies[1])),
+            Query(DeleteMessageQuery { for_everyone: true }),
+        )
+        .await
+        .unwrap();
+        let root = fetch_messages_by_id(&state, &[root])
+            .await
+            .unwrap()
+            .remove(0);
+        let first_reply = fetch_messages_by_id(&state, &[replies[0]])
+            .await
+            .unwrap()
+            .remove(0);
+        assert_eq!(root.thread_reply_count, 1);
+        assert_eq!(root.thread_last_reply_at, Some(first_reply.timestamp));
+        let page = fetch_history(
+            &state,
+            chat,
+            alice,
+            CHAT,
+            &history_query(None, None, None, 10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.messages[0].thread_reply_count, 1);
+    }
+
+    #[sqlx::test]
+    async fn publish_records_deliveries_only_for_queued_events(pool: SqlitePool) {
+        let alice = insert_user(&pool, "alice").await;
+        let bob = insert_user(&pool, "bob").await;
+        let carol = insert_user(&pool, "carol").await;
+        let dave = insert_user(&pool, "dave").await;
+        let chat = insert_chat(&pool, &[alice, bob, carol, dave]).await;
+        let message_id = insert_message(&pool, chat, alice).await;
+        let state = test_state(pool);
+        let _alice = connect(&state, alice, 4);
+        let _bob = connect(&state, bob, 4);
+        // Carol's queue is full, so she only gets the message once her connection catches up
+        let _carol = connect(&state, carol, 1);
+        publish(&state, &[carol], &renamed(chat, "busy", alice))
+            .await
+            .unwrap();
+        let message = fetch_messages_by_id(&state, &[message_id])
+            .await
+            .unwrap()
+            .remove(0);
+
+        publish(
+            &state,
+            &[alice, bob, carol, dave],
+            &WsEvent::Message(message),
+        )
+        .await
+        .unwrap();
+        let delivered_to: Vec<UserId> =
+            sqlx::query_scalar("SELECT user_id FROM message_deliveries WHERE message_id = ?")
+                .bind(message_id)
+                .fetch_all(&state.pool)
+                .await
+                .unwrap();
+        assert_eq!(delivered_to, [bob]);
+    }
+
+    #[test]
+    fn fts_query_quotes_words_and_prefixes_the_last() {
+        assert_eq!(fts_query("hello"), Some("\"hello\"*".to_string()));
+        assert_eq!(
+            fts_query("  quick   brown fo "),
+            Some("\"quick\" \"brown\" \"fo\"*".to_string())
+        );
+        // Operators and quotes are matched as text rather than interpreted
+        assert_eq!(
+            fts_query("a OR \"b\" NEAR(c)"),
+            Some("\"a\" \"OR\" \"\"\"b\"\"\" \"NEAR(c)\"*".to_string())
+        );
+        assert_eq!(fts_query(""), None);
+        assert_eq!(fts_query(" \t\n"), None);
+    }
+
+    #[test]
+    fn search_dates_cover_whole_days() {
+        assert_eq!(
+            parse_search_date("2026-02-19", false).unwrap(),
+            "2026-02-19T00:00:00+00:00"
+        );
+        assert_eq!(
+            parse_search_date("2026-02-19", true).unwrap(),
+            "2026-02-20T00:00:00+00:00"
+        );
+        assert_eq!(
+            parse_search_date("2026-12-31", true).unwrap(),
+            "2027-01-01T00:00:00+00:00"
+        );
+        // Timestamps are taken as given, whichever bound they are
+        assert_eq!(
+            parse_search_date("2026-02-19T12:30:00+02:00", true).unwrap(),
+            "2026-02-19T10:30:00+00:00"
+        );
+        for invalid in ["", "19.02.2026", "2026-02-30", "2026-02-19T25:00:00Z"] {
+            assert!(matches!(
+                parse_search_date(invalid, false),
+                Err(AppError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn snippets_escape_everything_but_the_marks() {
+        let snippet = format!("<b>{MATCH_START}fox{MATCH_END}</b> & \"it's\"");
+        assert_eq!(
+            highlight_snippet(&snippet),
+            "&lt;b&gt;<mark>fox</mark>&lt;/b&gt; &amp; &quot;it&#39;s&quot;"
+        );
+    }
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        assert_eq!(escape_like("alice"), "alice");
+        assert_eq!(escape_like("100%_sure"), "100\\%\\_sure");
+        assert_eq!(escape_like("back\\slash"), "back\\\\slash");
+    }
+
+    #[test]
+    fn user_search_cursors_split_at_the_first_bar() {
+        assert_eq!(
+            parse_user_search_cursor("0|alice").unwrap(),
+            (0, "alice".to_string())
+        );
+        assert_eq!(
+            parse_user_search_cursor("2|a|b").unwrap(),
+            (2, "a|b".to_string())
+        );
+        for invalid in ["", "alice", "x|alice", "|alice"] {
+            assert!(matches!(
+                parse_user_search_cursor(invalid),
+                Err(AppError::BadRequest(_))
+            ));
+        }
+    }
+
+    async fn search_users(
+        state: &AppState,
+        q: &str,
+        cursor: Option<String>,
+        limit: i64,
+    ) -> UserSearchResponse {
+        let query = UserSearchQuery {
+            q: q.to_string(),
+            cursor,
+            limit: Some(limit),
+        };
+        search_users_handler(State(state.clone()), auth_as(1), Query(query))
+            .await
+            .unwrap()
+            .0
+    }
+
+    fn usernames(page: &UserSearchResponse) -> Vec<&str> {
+        page.users.iter().map(|u| u.username.as_str()).collect()
+    }
+
+    #[sqlx::test]
+    async fn user_search_ranks_and_pages(pool: SqlitePool) {
+        for username in ["xalice", "bob", "alice2", "alice", "al_ice"] {
+            insert_user(&pool, username).await;
+        }
+        sqlx::query("UPDATE users SET display_name = 'Alice Bobson' WHERE username = 'bob'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let state = test_state(pool);
+
+        let first = search_users(&state, "ali", None, 2).await;
+        assert_eq!(usernames(&first), ["alice", "alice2"]);
+        let second = search_users(&state, "ali", first.next_cursor, 2).await;
+        assert_eq!(usernames(&second), ["bob", "xalice"]);
+        assert_eq!(second.next_cursor, None);
+
+        // Wildcards in the query match only themselves
+        let literal = search_users(&state, "al_", None, 10).await;
+        assert_eq!(usernames(&literal), ["al_ice"]);
+    }
+
+    #[sqlx::test]
+    async fn reforwarding_keeps_the_recorded_source(pool: SqlitePool) {
+        let alice = insert_user(&pool, "alice").await;
+        let bob = insert_user(&pool, "bob").await;
+        let source = insert_chat(&pool, &[alice, bob]).await;
+        let first = insert_chat(&pool, &[bob]).await;
+        let second = insert_chat(&pool, &[bob]).await;
+        let message = insert_message(&pool, source, alice).await;
+        let state = test_state(pool);
+
+        let forward = forward_message(&state, &auth_as(bob), source, message, first)
+            .await
+            .unwrap();
+        assert!(forward.forwarded);
+        assert_eq!(forward.forwarded_from_sender_id, Some(alice));
+        assert_eq!(forward.forwarded_from_chat_id, Some(source));
+
+        // As if alice's account and the source chat had been deleted since
+        sqlx::query(
+            "UPDATE messages SET forwarded_from_sender_id = NULL, forwarded_from_chat_id = NULL WHERE id = ?",
+        )
+        .bind(forward.id)
+        .execute(&state.pool)
+        .await
+        .unwrap();
+        let again = forward_message(&state, &auth_as(bob), first, forward.id, second)
+            .await
+            .unwrap();
+        assert!(again.forwarded);
+        assert_eq!(again.forwarded_from_sender_id, None);
+        assert_eq!(again.forwarded_from_chat_id, None);
+    }
+
+    #[sqlx::test]
+    async fn forwarded_messages_cannot_be_edited(pool: SqlitePool) {
+        let alice = insert_user(&pool, "alice").await;
+        let bob = insert_user(&pool, "bob").await;
+        let source = insert_chat(&pool, &[alice, bob]).await;
+        let target = insert_chat(&pool, &[bob]).await;
+        let message = insert_message(&pool, source, alice).await;
+        let state = test_state(pool);
+
+        let forward = forward_message(&state, &auth_as(bob), source, message, target)
+            .await
+            .unwrap();
+        let edit = edit_message(
+            &state,
+            &auth_as(bob),
+            target,
+            forward.id,
+            "I never said that".to_string(),
+        )
+        .await;
+        assert!(matches!(edit, Err(AppError::BadRequest(_))));
+        let stored = fetch_message(&state, target, forward.id).await.unwrap();
+        assert_eq!(stored.content.as_deref(), Some("hi"));
+        assert_eq!(stored.edited_at, None);
+    }
+
+    #[test]
+    fn reactions_must_be_single_known_emoji() {
+        let emoji = |text: &str| Reaction {
+            emoji: Some(text.to_string()),
+            custom_emoji_id: None,
+        };
+        for valid in ["👍", "👍🏽", "👨‍👩‍👧", "🇯🇵"] {
+            assert_eq!(
+                validate_reaction(emoji(valid)).unwrap().emoji.as_deref(),
+                Some(valid)
+            );
+        }
+        // Unqualified forms are stored like their fully qualified ones
+        assert_eq!(
+            validate_reaction(emoji("\u{2764}"))
+                .unwrap()
+                .emoji
+                .as_deref(),
+            Some("\u{2764}\u{fe0f}")
+        );
+        for invalid in ["", "lol", "a", "👍👍", "👍 ", "<b>"] {
+            assert!(matches!(
+                validate_reaction(emoji(invalid)),
+                Err(AppError::BadRequest(_))
+            ));
+        }
+        let neither = Reaction {
+            emoji: None,
+            custom_emoji_id: None,
+        };
+        assert!(matches!(
+            validate_reaction(neither),
+            Err(AppError::BadRequest(_))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn custom_emoji_are_scoped_to_their_server(pool: SqlitePool) {
+        let owner = insert_user(&pool, "alice").await;
+        let member = insert_user(&pool, "bob").await;
+        let server: ServerId =
+            sqlx::query_scalar("INSERT INTO servers (name) VALUES ('guild') RETURNING id")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        for (user_id, role) in [(owner, "owner"), (member, "member")] {
+            sqlx::query("INSERT INTO server_members (server_id, user_id, role) VALUES (?, ?, ?)")
+                .bind(server)
+                .bind(user_id)
+                .bind(role)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let channel = insert_chat(&pool, &[owner, member]).await;
+        sqlx::query("UPDATE chats SET chat_type = 'server', server_id = ? WHERE id = ?")
+            .bind(server)
+            .bind(channel)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let group = insert_chat(&pool, &[owner, member]).await;
+        let in_channel = insert_message(&pool, channel, owner).await;
+        let in_group = insert_message(&pool, group, owner).await;
+        let state = test_state(pool);
+        let create = |user_id: UserId, name: &str| {
+            create_custom_emoji_handler(
+                State(state.clone()),
+                auth_as(user_id),
+                Path(server),
+                Json(CreateCustomEmoji {
+                    name: name.to_string(),
+                    image: FileAssetIn {
+                        r#type: FileType::Picture,
+                        url: "/uploads/party.png".to_string(),
+                        filename: "party.png".to_string(),
+                        mime_type: Some("image/png".to_string()),
+                        size_bytes: 1,
+                    },
+                }),
+            )
+        };
+
+        assert!(matches!(
+            create(member, "party").await,
+            Err(AppError::AuthError(_))
+        ));
+        assert!(matches!(
+            create(owner, "Party!").await,
+            Err(AppError::BadRequest(_))
+        ));
+        let Json(emoji) = create(owner, "party").await.unwrap();
+        assert!(matches!(
+            create(owner, "party").await,
+            Err(AppError::Conflict(_))
+        ));
+
+        let custom = || Reaction {
+            emoji: None,
+            custom_emoji_id: Some(emoji.id),
+        };
+        add_reaction(&state, &auth_as(member), channel, in_channel, custom())
+            .await
+            .unwrap();
+        assert!(matches!(
+            add_reaction(&state, &auth_as(member), group, in_group, custom()).await,
+            Err(AppError::BadRequest(_))
+        ));
+
+        delete_custom_emoji_handler(
+            State(state.clone()),
+            auth_as(owner),
+            Path((server, emoji.id)),
+        )
+        .await
+        .unwrap();
+        let reactions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM message_reactions")
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(reactions, 0);
+    }
 }
@@ -1,11 +1,12 @@
 use axum::{
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use dashmap::DashMap;
 use dotenvy::dotenv;
 use sqlx::sqlite::SqlitePoolOptions;
 use std::env;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tower_http::trace::TraceLayer;
@@ -15,18 +16,48 @@ mod handlers;
 mod models;
 
 use handlers::{
-    get_history_handler, get_user_handler, initiate_direct_chat_handler, list_chats_handler,
-    get_chat_handler, login_handler, search_users_handler, upload_handler, ws_handler,
+    add_members_handler, add_reaction_handler, add_server_members_handler, change_password_handler,
+    close_connection_handler, create_category_handler, create_channel_handler,
+    create_custom_emoji_handler, create_group_chat_handler, create_server_handler,
+    delete_custom_emoji_handler, delete_message_handler, edit_message_handler,
+    forward_message_handler, get_chat_handler, get_history_handler, get_server_handler,
+    get_thread_handler, get_user_handler, initiate_direct_chat_handler, leave_chat_handler,
+    leave_server_handler, list_chats_handler, list_connections_handler, list_custom_emoji_handler,
+    list_members_handler, list_message_edits_handler, list_pins_handler,
+    list_server_members_handler, list_sessions_handler, login_handler, logout_handler,
+    mark_read_handler, pin_message_handler, refresh_handler, register_handler,
+    remove_member_handler, remove_reaction_handler, remove_server_member_handler,
+    rename_chat_handler, revoke_all_sessions_handler, revoke_session_handler,
+    search_messages_handler, search_users_handler, unpin_message_handler, update_channel_handler,
+    update_member_role_handler, update_server_layout_handler, update_server_member_role_handler,
+    upload_handler, ws_handler,
 };
 use models::AppState;
 use tower_http::services::ServeDir;
 
+const DEFAULT_DELETE_FOR_EVERYONE_WINDOW_SECS: i64 = 3600 * 48; // 48 hours
+const DEFAULT_WS_QUEUE_CAPACITY: usize = 100;
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
     tracing_subscriber::fmt::init();
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let jwt_secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+    let delete_for_everyone_window_secs = env::var("DELETE_FOR_EVERYONE_WINDOW_SECS")
+        .map(|v| {
+            v.parse()
+                .expect("DELETE_FOR_EVERYONE_WINDOW_SECS must be a number of seconds")
+        })
+        .unwrap_or(DEFAULT_DELETE_FOR_EVERYONE_WINDOW_SECS);
+    let connection_queue_capacity = env::var("WS_QUEUE_CAPACITY")
+        .map(|v| {
+            v.parse()
+                .ok()
+                .filter(|&capacity| capacity > 0)
+                .expect("WS_QUEUE_CAPACITY must be a positive number")
+        })
+        .unwrap_or(DEFAULT_WS_QUEUE_CAPACITY);
     let pool = SqlitePoolOptions::new()
         .max_connections(5)
         .connect(&database_url)
@@ -36,15 +67,112 @@ async fn main() {
         pool,
         active_connections: Arc::new(DashMap::new()),
         jwt_secret,
+        delete_for_everyone_window_secs,
+        connection_queue_capacity,
+        typing: Arc::new(DashMap::new()),
+        publish_lock: Arc::new(tokio::sync::Mutex::new(())),
     };
     let app = Router::new()
+        .route("/register", post(register_handler))
         .route("/login", post(login_handler))
+        .route("/refresh", post(refresh_handler))
+        .route("/logout", post(logout_handler))
+        .route(
+            "/sessions",
+            get(list_sessions_handler).delete(revoke_all_sessions_handler),
+        )
+        .route("/sessions/:id", delete(revoke_session_handler))
+        .route("/connections", get(list_connections_handler))
+        .route("/connections/:id", delete(close_connection_handler))
+        .route("/users/me/password", put(change_password_handler))
         .route("/users/:id", get(get_user_handler))
         .route("/users", get(search_users_handler))
-        .route("/chats", get(list_chats_handler))
-        .route("/chats/:chat_id", get(get_chat_handler))
+        .route("/search/messages", get(search_messages_handler))
+        .route(
+            "/chats",
+            get(list_chats_handler).post(create_group_chat_handler),
+        )
+        .route(
+            "/chats/:chat_id",
+            get(get_chat_handler).patch(rename_chat_handler),
+        )
+        .route(
+            "/chats/:chat_id/members",
+            get(list_members_handler).post(add_members_handler),
+        )
+        .route(
+            "/chats/:chat_id/members/:user_id",
+            delete(remove_member_handler),
+        )
+        .route(
+            "/chats/:chat_id/members/:user_id/role",
+            put(update_member_role_handler),
+        )
+        .route("/chats/:chat_id/leave", post(leave_chat_handler))
+        .route("/chats/:chat_id/read", post(mark_read_handler))
+        .route("/chats/:chat_id/pins", get(list_pins_handler))
+        .route(
+            "/chats/:chat_id/pins/:message_id",
+            put(pin_message_handler).delete(unpin_message_handler),
+        )
         .route("/chats/initiate", post(initiate_direct_chat_handler))
         .route("/chats/:chat_id/messages", get(get_history_handler))
+        .route(
+            "/chats/:chat_id/messages/:message_id",
+            patch(edit_message_handler).delete(delete_message_handler),
+        )
+        .route(
+            "/chats/:chat_id/messages/:message_id/edits",
+            get(list_message_edits_handler),
+        )
+        .route(
+            "/chats/:chat_id/messages/:message_id/thread",
+            get(get_thread_handler),
+        )
+        .route(
+            "/chats/:chat_id/messages/:message_id/forward",
+            post(forward_message_handler),
+        )
+        .route(
+            "/chats/:chat_id/messages/:message_id/reactions",
+            post(add_reaction_handler).delete(remove_reaction_handler),
+        )
+        .route("/servers", post(create_server_handler))
+        .route("/servers/:server_id", get(get_server_handler))
+        .route(
+            "/servers/:server_id/members",
+            get(list_server_members_handler).post(add_server_members_handler),
+        )
+        .route(
+            "/servers/:server_id/members/:user_id",
+            delete(remove_server_member_handler),
+        )
+        .route(
+            "/servers/:server_id/members/:user_id/role",
+            put(update_server_member_role_handler),
+        )
+        .route("/servers/:server_id/leave", post(leave_server_handler))
+        .route(
+            "/servers/:server_id/categories",
+            post(create_category_handler),
+        )
+        .route("/servers/:server_id/channels", post(create_channel_handler))
+        .route(
+            "/servers/:server_id/channels/:chat_id",
+            patch(update_channel_handler),
+        )
+        .route(
+            "/servers/:server_id/layout",
+            put(update_server_layout_handler),
+        )
+        .route(
+            "/servers/:server_id/emoji",
+            get(list_custom_emoji_handler).post(create_custom_emoji_handler),
+        )
+        .route(
+            "/servers/:server_id/emoji/:emoji_id",
+            delete(delete_custom_emoji_handler),
+        )
         .route("/upload", post(upload_handler))
         .nest_service("/uploads", ServeDir::new("uploads"))
         .route("/ws", get(ws_handler))
@@ -52,5 +180,10 @@ async fn main() {
         .with_state(state);
     let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
     tracing::info!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
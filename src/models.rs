@@ -1,19 +1,68 @@
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
 
 pub type UserId = i64;
 pub type ChatId = i64;
 pub type MessageId = i64;
 pub type FileId = i64;
+pub type SessionId = i64;
+pub type ServerId = i64;
+pub type CategoryId = i64;
+pub type MessageEditId = i64;
+pub type EventSeq = i64;
+pub type ConnectionId = String;
+pub type CustomEmojiId = i64;
 
 #[derive(Clone)]
 pub struct AppState {
     pub pool: SqlitePool,
-    pub active_connections: Arc<DashMap<String, broadcast::Sender<String>>>,
+    pub active_connections: Arc<DashMap<UserId, HashMap<ConnectionId, ConnectionHandle>>>,
     pub jwt_secret: String,
+    pub delete_for_everyone_window_secs: i64, // How long senders may delete for everyone
+    pub connection_queue_capacity: usize,     // Events buffered per WebSocket connection
+    pub typing: Arc<DashMap<(ChatId, UserId), TypingIndicator>>,
+    // Held while events are sequenced and queued, so every queue receives them in seq order
+    pub publish_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// A user typing in a chat. `expiry` is the single task that ends the indicator once
+/// `expires_at` passes; renewing the indicator only moves the deadline.
+#[derive(Debug)]
+pub struct TypingIndicator {
+    pub expires_at: Instant,
+    pub expiry: tokio::task::AbortHandle,
+}
+
+/// A serialized event queued for a user's live connections. `seq` is set for events recorded
+/// in the user's event log.
+#[derive(Debug, Clone)]
+pub struct OutboundEvent {
+    pub seq: Option<EventSeq>,
+    pub json: String,
+}
+
+/// A live WebSocket connection. Events for it are queued on `sender`; notifying `close` makes
+/// it shut down, and `lagged` makes it catch up from the event log after its queue overflowed.
+#[derive(Debug, Clone)]
+pub struct ConnectionHandle {
+    pub info: Connection,
+    pub sender: mpsc::Sender<OutboundEvent>,
+    pub close: Arc<Notify>,
+    pub lagged: Arc<Notify>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Connection {
+    pub id: ConnectionId,
+    pub session_id: SessionId,
+    pub device_id: Option<String>, // Chosen by the client when connecting
+    pub connected_at: String,
+    pub status: PresenceStatus,
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
@@ -23,6 +72,21 @@ pub struct User {
     pub display_name: Option<String>,
     pub bio: Option<String>,
     pub image_id: Option<FileId>,
+    pub last_seen_at: Option<String>, // Set when the user's last connection closes
+    #[sqlx(skip)]
+    pub presence: PresenceStatus,
+}
+
+/// Whether a user is connected. `away` is set by the client, e.g. when the app is in the
+/// background; a user is `online` if any of their connections is.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    #[default]
+    Offline,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, sqlx::Type)]
@@ -39,18 +103,59 @@ pub struct Chat {
     pub name: Option<String>,
     pub chat_type: ChatType,
     pub created_at: String,
+    pub server_id: Option<ServerId>,
     #[sqlx(skip)]
     pub participants: Vec<UserId>,
+    pub pin_count: i64,
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
 pub struct ChatParticipant {
     pub chat_id: ChatId,
     pub user_id: UserId,
+    pub role: ChatRole,
     pub joined_at: String,
 }
 
+/// Role of a participant within a chat. Variants are ordered by privilege.
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, sqlx::Type,
+)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    Member,
+    Admin,
+    Owner,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChatPermission {
+    RenameChat,
+    ManageMembers,
+    ManageRoles,
+    ManageChannels,
+    DeleteOthersMessages,
+    PinMessages,
+    ManageEmoji,
+}
+
+impl ChatRole {
+    /// Whether a participant with this role may perform `permission` in a chat of `chat_type`.
+    /// Direct chats have no management; both participants may pin.
+    pub fn allows(self, chat_type: &ChatType, permission: ChatPermission) -> bool {
+        use ChatPermission::*;
+        match chat_type {
+            ChatType::Direct => permission == PinMessages,
+            ChatType::Group | ChatType::Server => match self {
+                ChatRole::Owner => true,
+                ChatRole::Admin => permission != ManageRoles,
+                ChatRole::Member => false,
+            },
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, sqlx::Type)]
 #[sqlx(type_name = "TEXT", rename_all = "lowercase")]
 pub enum FileType {
@@ -78,13 +183,158 @@ pub struct Message {
     pub sender_id: UserId,
     pub content: Option<String>,
     pub timestamp: String,
+    pub edited_at: Option<String>,
+    pub deleted_at: Option<String>, // Set on tombstones of messages deleted for everyone
+    pub reply_to: Option<MessageId>,
+    pub thread_root_id: Option<MessageId>, // Set on thread replies
+    // Replies in the thread started by this message
+    pub thread_reply_count: i64,
+    pub thread_last_reply_at: Option<String>,
+    pub forwarded: bool,
+    // Set on forwarded messages, pointing at where the message was first sent. The chat is only
+    // returned to viewers who take part in it.
+    pub forwarded_from_sender_id: Option<UserId>,
+    pub forwarded_from_chat_id: Option<ChatId>,
     #[sqlx(skip)]
     pub files: Vec<MediaAsset>,
+    #[sqlx(skip)]
+    pub reply_preview: Option<MessagePreview>, // The message replied to
+    // Filled in when returned to a single viewer, e.g. in the history
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reactions: Option<Vec<ReactionSummary>>,
+    // Only filled in for the sender's own messages
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<MessageStatus>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The stored columns of a `Message` with the summary of its thread, as loaded by the
+/// compile-time checked queries.
+#[derive(Debug)]
+pub struct MessageRow {
+    pub id: MessageId,
+    pub chat_id: ChatId,
+    pub sender_id: UserId,
+    pub content: Option<String>,
+    pub timestamp: String,
+    pub edited_at: Option<String>,
+    pub deleted_at: Option<String>,
+    pub reply_to: Option<MessageId>,
+    pub thread_root_id: Option<MessageId>,
+    pub thread_reply_count: i64,
+    pub thread_last_reply_at: Option<String>,
+    pub forwarded: bool,
+    pub forwarded_from_sender_id: Option<UserId>,
+    pub forwarded_from_chat_id: Option<ChatId>,
+}
+
+impl From<MessageRow> for Message {
+    fn from(row: MessageRow) -> Self {
+        Message {
+            id: row.id,
+            chat_id: row.chat_id,
+            sender_id: row.sender_id,
+            content: row.content,
+            timestamp: row.timestamp,
+            edited_at: row.edited_at,
+            deleted_at: row.deleted_at,
+            reply_to: row.reply_to,
+            thread_root_id: row.thread_root_id,
+            thread_reply_count: row.thread_reply_count,
+            thread_last_reply_at: row.thread_last_reply_at,
+            forwarded: row.forwarded,
+            forwarded_from_sender_id: row.forwarded_from_sender_id,
+            forwarded_from_chat_id: row.forwarded_from_chat_id,
+            files: Vec::new(),
+            reply_preview: None,
+            reactions: None,
+            status: None,
+        }
+    }
+}
+
+/// A reaction: exactly one of a unicode emoji or a custom emoji id.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Reaction {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emoji: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_emoji_id: Option<CustomEmojiId>,
+}
+
+/// How many users reacted to a message with one reaction.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReactionSummary {
+    #[serde(flatten)]
+    pub reaction: Reaction,
+    pub count: i64,
+    pub reacted_by_me: bool,
+}
+
+/// Progress of a message towards the other participants of its chat.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum MessageStatus {
+    Sent,
+    Delivered, // Pushed to a live connection of every recipient
+    Read,
+}
+
+/// A message pinned in its chat.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PinnedMessage {
+    pub message: Message,
+    pub pinned_by: UserId,
+    pub pinned_at: String,
+}
+
+/// A previous version of an edited message.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct MessageEdit {
+    pub id: MessageEditId,
+    pub message_id: MessageId,
+    pub content: Option<String>,
+    pub edited_at: String, // When this version was replaced
+}
+
+// No Debug on request bodies carrying secrets, so they cannot end up in logs
+#[derive(Serialize, Deserialize)]
 pub struct CreateUser {
     pub username: String,
+    pub password: String,
+    pub device_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+    pub device_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct Session {
+    pub id: SessionId,
+    pub device_name: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: String,
+    pub last_used_at: String,
+    #[sqlx(skip)]
+    pub current: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -92,6 +342,119 @@ pub struct InitiateChat {
     pub target_id: UserId, // For starting a direct chat
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateGroupChat {
+    pub name: String,
+    pub member_ids: Vec<UserId>, // The creator is always added
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddMembers {
+    pub user_ids: Vec<UserId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenameChat {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateMemberRole {
+    pub role: ChatRole,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct ServerMember {
+    pub server_id: ServerId,
+    pub user_id: UserId,
+    pub role: ChatRole,
+    pub joined_at: String,
+}
+
+/// A text channel of a server. Channels are chats with `chat_type` `server`.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct Channel {
+    pub id: ChatId,
+    pub name: Option<String>,
+    pub category_id: Option<CategoryId>,
+    pub position: i64,
+    pub archived_at: Option<String>,
+    // Read state of the requesting user
+    pub last_read_message_id: Option<MessageId>,
+    pub unread_count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChannelCategory {
+    pub id: CategoryId,
+    pub name: String,
+    pub position: i64,
+    pub channels: Vec<Channel>,
+}
+
+/// A server with its channel tree, as seen by one member.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerTree {
+    pub id: ServerId,
+    pub name: String,
+    pub created_at: String,
+    pub role: ChatRole,
+    pub categories: Vec<ChannelCategory>,
+    pub channels: Vec<Channel>, // Channels without a category
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateServer {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateCategory {
+    pub name: String,
+}
+
+/// An image uploaded to a server that its members can react with.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct CustomEmoji {
+    pub id: CustomEmojiId,
+    pub server_id: ServerId,
+    pub name: String,
+    pub url: String,
+    pub created_by: Option<UserId>, // None once the uploader's account is deleted
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateCustomEmoji {
+    pub name: String,
+    pub image: FileAssetIn,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateChannel {
+    pub name: String,
+    pub category_id: Option<CategoryId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateChannel {
+    pub name: Option<String>,
+    pub archived: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CategoryLayout {
+    pub id: CategoryId,
+    pub channel_ids: Vec<ChatId>,
+}
+
+/// Full ordering of a server's categories and channels; positions follow list order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerLayout {
+    pub categories: Vec<CategoryLayout>,
+    pub channels: Vec<ChatId>, // Channels without a category
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileAssetIn {
     pub r#type: FileType,
@@ -114,11 +477,231 @@ pub struct WsMessageIn {
     pub chat_id: ChatId,
     pub content: Option<String>,
     pub files: Option<Vec<FileAssetIn>>,
+    /// Client-generated id (e.g. a UUID); resending with the same nonce returns the stored
+    /// message instead of creating a duplicate.
+    pub client_nonce: Option<String>,
+    pub reply_to: Option<MessageId>, // Message in the same chat being replied to
+    pub thread_root_id: Option<MessageId>, // Posts the message as a reply in this message's thread
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EditMessage {
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteMessageQuery {
+    #[serde(default)]
+    pub for_everyone: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForwardMessage {
+    pub target_chat_id: ChatId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WsForwardMessageIn {
+    pub chat_id: ChatId,
+    pub message_id: MessageId,
+    pub target_chat_id: ChatId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WsEditMessageIn {
+    pub chat_id: ChatId,
+    pub message_id: MessageId,
+    pub content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WsTypingIn {
+    pub chat_id: ChatId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarkRead {
+    pub message_id: MessageId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WsMarkReadIn {
+    pub chat_id: ChatId,
+    pub message_id: MessageId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WsReactionIn {
+    pub chat_id: ChatId,
+    pub message_id: MessageId,
+    #[serde(flatten)]
+    pub reaction: Reaction,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WsSetPresenceIn {
+    pub status: PresenceStatus,
+}
+
+/// Frames accepted from clients over the WebSocket. The optional `request_id` is echoed back
+/// in the `ack` or `error` frame answering the request.
+#[derive(Debug, Deserialize)]
+pub struct WsClientEnvelope {
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub frame: WsClientFrame,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum WsClientFrame {
+    SendMessage(WsMessageIn),
+    EditMessage(WsEditMessageIn),
+    ForwardMessage(WsForwardMessageIn),
+    TypingStart(WsTypingIn),
+    TypingStop(WsTypingIn),
+    SetPresence(WsSetPresenceIn),
+    MarkRead(WsMarkReadIn),
+    AddReaction(WsReactionIn),
+    RemoveReaction(WsReactionIn),
+}
+
+/// Events pushed to clients over the WebSocket.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum WsEvent {
+    Message(Message),
+    ThreadMessage(Message), // A new reply in a thread
+    ThreadUpdated {
+        chat_id: ChatId,
+        root_message_id: MessageId,
+        reply_count: i64,
+        last_reply_at: Option<String>,
+    },
+    MessageEdited(Message),
+    MessageDeleted {
+        chat_id: ChatId,
+        message_id: MessageId,
+        deleted_by: UserId,
+        for_everyone: bool, // false: hidden only for deleted_by, sent to their own connections
+    },
+    ChatCreated(Chat),
+    MembersAdded {
+        chat_id: ChatId,
+        user_ids: Vec<UserId>,
+        added_by: UserId,
+    },
+    MemberRemoved {
+        chat_id: ChatId,
+        user_id: UserId,
+        removed_by: UserId, // Equal to user_id when the member left
+    },
+    ChatRenamed {
+        chat_id: ChatId,
+        name: String,
+        renamed_by: UserId,
+    },
+    MemberRoleChanged {
+        chat_id: ChatId,
+        user_id: UserId,
+        role: ChatRole,
+        changed_by: UserId,
+    },
+    ServerUpdated {
+        server_id: ServerId,
+        updated_by: UserId,
+    },
+    ReadReceipt {
+        chat_id: ChatId,
+        user_id: UserId,
+        last_read_message_id: MessageId,
+    },
+    MessagePinned {
+        chat_id: ChatId,
+        message_id: MessageId,
+        pinned_by: UserId,
+    },
+    MessageUnpinned {
+        chat_id: ChatId,
+        message_id: MessageId,
+        unpinned_by: UserId,
+    },
+    ReactionAdded {
+        chat_id: ChatId,
+        message_id: MessageId,
+        user_id: UserId,
+        #[serde(flatten)]
+        reaction: Reaction,
+    },
+    ReactionRemoved {
+        chat_id: ChatId,
+        message_id: MessageId,
+        user_id: UserId,
+        #[serde(flatten)]
+        reaction: Reaction,
+    },
+    // Sent to the sender once every recipient has received the message
+    MessageDelivered {
+        chat_id: ChatId,
+        message_id: MessageId,
+    },
+    // Ephemeral: not recorded in the event log and carry no seq
+    TypingStarted {
+        chat_id: ChatId,
+        user_id: UserId,
+    },
+    TypingStopped {
+        chat_id: ChatId,
+        user_id: UserId,
+    },
+    PresenceChanged {
+        user_id: UserId,
+        status: PresenceStatus,
+        last_seen_at: Option<String>,
+    },
+    // Replies to a client frame, sent only to the connection that sent it
+    Ack {
+        request_id: Option<String>,
+        // Set when the frame created or edited a message
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message_id: Option<MessageId>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<Message>,
+    },
+    Error {
+        request_id: Option<String>,
+        code: String,
+        message: String,
+    },
+    // Sent once after connecting, when replay is done and live delivery starts
+    Ready {
+        seq: EventSeq,
+    },
+    // The missed events are no longer in the log; state must be refetched
+    ResyncRequired {
+        seq: EventSeq,
+    },
+}
+
+/// An event as sent to clients, numbered in the recipient's event log.
+#[derive(Debug, Serialize)]
+pub struct SequencedEvent<'a> {
+    pub seq: EventSeq,
+    #[serde(flatten)]
+    pub event: &'a WsEvent,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WsConnectQuery {
+    pub since: Option<EventSeq>, // Last seq the client received; missed events are replayed
+    pub device_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthResponse {
     pub token: String,
+    pub refresh_token: String,
+    pub expires_in: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -126,6 +709,7 @@ pub struct Claims {
     pub sub: String,
     pub user_id: UserId,
     pub username: String,
+    pub sid: SessionId,
     pub exp: usize,
 }
 
@@ -141,13 +725,110 @@ pub struct InitiateDirectChatResponse {
     pub status: ChatStatus,
 }
 
+/// A chat as listed for one user, with their read state.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatSummary {
+    #[serde(flatten)]
+    pub chat: Chat,
+    pub last_read_message_id: Option<MessageId>,
+    pub unread_count: i64,
+    pub last_message: Option<MessagePreview>,
+}
+
+/// A compact view of a message, used for the chat list and quoted replies.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessagePreview {
+    pub id: MessageId,
+    pub sender_id: UserId,
+    pub snippet: Option<String>, // Start of the content; None for deleted or attachment-only messages
+    pub timestamp: String,
+    pub attachment: Option<FileType>, // Type of the first attachment, if any
+    pub deleted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatListQuery {
+    pub cursor: Option<String>, // `next_cursor` of the previous page
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatListResponse {
+    pub chats: Vec<ChatSummary>, // Direct and group chats, most recently active first
+    pub servers: Vec<ServerTree>, // Only on the first page
+    pub next_cursor: Option<String>,
+}
+
+/// Cursor parameters for `GET /chats/:chat_id/messages`. At most one of `before`, `after`
+/// and `around` may be given; without any of them the latest messages are returned.
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    pub before: Option<MessageId>,
+    pub after: Option<MessageId>,
+    pub around: Option<MessageId>,
+    pub limit: Option<i64>,
+    #[serde(default)]
+    pub include_threads: bool, // Also return thread replies in the chat history
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatHistoryResponse {
     pub chat_id: ChatId,
+    /// Ordered from oldest to newest.
     pub messages: Vec<Message>,
+    /// Pass as `before` to load older messages; `None` once the start of the chat is reached.
+    pub next_cursor: Option<MessageId>,
+    /// Pass as `after` to load newer messages; `None` when the page ends at the latest message.
+    pub prev_cursor: Option<MessageId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadHistoryResponse {
+    pub root: Message,
+    /// A page of the thread's replies, paginated like the chat history.
+    #[serde(flatten)]
+    pub history: ChatHistoryResponse,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageSearchQuery {
+    pub q: String,
+    pub chat_id: Option<ChatId>,
+    pub sender_id: Option<UserId>,
+    pub from: Option<String>, // RFC 3339 timestamp or YYYY-MM-DD, inclusive
+    pub to: Option<String>,   // RFC 3339 timestamp (exclusive) or YYYY-MM-DD (inclusive)
+    pub has_attachment: Option<bool>,
+    pub cursor: Option<MessageId>, // `next_cursor` of the previous page
+    pub limit: Option<i64>,
+}
+
+/// A message matching a search, with the matched terms highlighted in `snippet`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageSearchResult {
+    pub message_id: MessageId,
+    pub chat_id: ChatId,
+    pub sender_id: UserId,
+    pub thread_root_id: Option<MessageId>,
+    pub timestamp: String,
+    pub snippet: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageSearchResponse {
+    pub results: Vec<MessageSearchResult>, // Newest first
+    pub next_cursor: Option<MessageId>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct UserSearchQuery {
-    pub username: Option<String>,
+    #[serde(alias = "username")]
+    pub q: String, // Matched against usernames and display names
+    pub cursor: Option<String>, // `next_cursor` of the previous page
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserSearchResponse {
+    pub users: Vec<User>,
+    pub next_cursor: Option<String>,
 }